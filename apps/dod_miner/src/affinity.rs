@@ -0,0 +1,232 @@
+use std::collections::BTreeSet;
+use std::fs;
+
+/// How the OS schedules the mining threads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkerPriority {
+    #[default]
+    Normal,
+    /// Nice level, -20 to 19. Below 0 needs `CAP_SYS_NICE`.
+    Nice(i32),
+    /// `SCHED_IDLE`, only cpu time nothing else wants.
+    Idle,
+}
+
+/// Which cores the mining threads run on and at what priority.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerPlacement {
+    /// Worker `i` is pinned to `cores[i % cores.len()]`, unpinned if `None`.
+    pub cores: Option<Vec<usize>>,
+    pub priority: WorkerPriority,
+}
+
+/// Cpus a Linux `cpu_set_t` has room for, numbered from 0.
+pub const MAX_CPUS: usize = 1024;
+
+/// A Linux cpu list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("Invalid cpu list {}, expected e.g. 0-3,8", s);
+    let mut cpus = vec![];
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((a, b)) => (a, b),
+            None => (part, part),
+        };
+        let first = first.trim().parse::<usize>().map_err(|_| invalid())?;
+        let last = last.trim().parse::<usize>().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        if last >= MAX_CPUS {
+            return Err(format!(
+                "Invalid cpu {}, cpus are numbered below {}",
+                last, MAX_CPUS
+            ));
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        return Err(invalid());
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// The cpus of NUMA node `node`.
+pub fn numa_node_cpus(node: u32) -> Result<Vec<usize>, String> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let list = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    parse_cpu_list(&list)
+}
+
+impl WorkerPlacement {
+    pub fn core_for(&self, worker: u32) -> Option<usize> {
+        self.cores
+            .as_ref()
+            .filter(|c| !c.is_empty())
+            .map(|c| c[worker as usize % c.len()])
+    }
+
+    /// Fails for a core outside `allowed`, the cpus of the process's affinity mask,
+    /// which the workers could not be pinned to.
+    pub fn check_cores(&self, allowed: &BTreeSet<usize>) -> Result<(), String> {
+        let outside: Vec<String> = self
+            .cores
+            .iter()
+            .flatten()
+            .filter(|c| !allowed.contains(c))
+            .map(|c| c.to_string())
+            .collect();
+        if outside.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Cpus {} are not in the affinity mask of the miner",
+                outside.join(",")
+            ))
+        }
+    }
+
+    /// Pins the calling thread as worker `worker` and sets its priority.
+    pub fn apply(&self, worker: u32) -> Result<(), String> {
+        if let Some(core) = self.core_for(worker) {
+            pin_current_thread(core)?;
+        }
+        set_current_thread_priority(self.priority)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> Result<(), String> {
+    // CPU_SET panics past the end of the set
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(format!("Error pinning to cpu {}: no such cpu", core));
+    }
+    // SAFETY: cpu_set_t is plain data, and 0 stands for the calling thread
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(format!(
+                "Error pinning to cpu {}: {}",
+                core,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_thread_priority(priority: WorkerPriority) -> Result<(), String> {
+    // SAFETY: both only change the scheduling of the calling thread
+    let res = unsafe {
+        match priority {
+            WorkerPriority::Normal => return Ok(()),
+            WorkerPriority::Nice(nice) => {
+                // per thread on Linux, given the thread id
+                let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+                libc::setpriority(libc::PRIO_PROCESS, tid, nice)
+            }
+            WorkerPriority::Idle => {
+                let param = libc::sched_param { sched_priority: 0 };
+                libc::sched_setscheduler(0, libc::SCHED_IDLE, &param)
+            }
+        }
+    };
+    if res != 0 {
+        return Err(format!(
+            "Error setting priority {:?}: {}",
+            priority,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(core: usize) -> Result<(), String> {
+    Err(format!(
+        "Pinning to cpu {} is only supported on Linux",
+        core
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_current_thread_priority(priority: WorkerPriority) -> Result<(), String> {
+    match priority {
+        WorkerPriority::Normal => Ok(()),
+        _ => Err(format!(
+            "Priority {:?} is only supported on Linux",
+            priority
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::affinity::{parse_cpu_list, WorkerPlacement, WorkerPriority};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8\n"), Ok(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("5,1-2,2"), Ok(vec![1, 2, 5]));
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("1020-1024").is_err());
+        assert!(parse_cpu_list("0-18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn places_workers_round_robin() {
+        let placement = WorkerPlacement {
+            cores: Some(vec![2, 3]),
+            priority: WorkerPriority::Normal,
+        };
+        assert_eq!(placement.core_for(0), Some(2));
+        assert_eq!(placement.core_for(3), Some(3));
+        assert_eq!(WorkerPlacement::default().core_for(0), None);
+    }
+
+    #[test]
+    fn checks_cores_against_mask() {
+        let allowed = BTreeSet::from([0, 1, 2, 3]);
+        let placement = WorkerPlacement {
+            cores: Some(vec![2, 3]),
+            priority: WorkerPriority::Normal,
+        };
+        assert_eq!(placement.check_cores(&allowed), Ok(()));
+        assert_eq!(WorkerPlacement::default().check_cores(&allowed), Ok(()));
+        let placement = WorkerPlacement {
+            cores: Some(vec![3, 4, 8]),
+            priority: WorkerPriority::Normal,
+        };
+        assert_eq!(
+            placement.check_cores(&allowed),
+            Err("Cpus 4,8 are not in the affinity mask of the miner".to_string())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn applies_to_worker_thread() {
+        // a cpu the tests may run on, which need not include cpu 0
+        let core = *dod_cpu::threads::get_affinity_cpus()
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap();
+        let placement = WorkerPlacement {
+            cores: Some(vec![core]),
+            priority: WorkerPriority::Idle,
+        };
+        // a thread of its own, the test harness reuses its threads
+        let res = std::thread::spawn(move || placement.apply(0))
+            .join()
+            .unwrap();
+        assert_eq!(res, Ok(()));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::engine::{cycles_from_trillions, MinerEngine};
+use crate::metrics::METRICS_CONTENT_TYPE;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::net::SocketAddr;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// Where `--api` listens when no address is given, only reachable from the box itself.
+pub const DEFAULT_API_ADDR: &str = "127.0.0.1:7878";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThreadsRequest {
+    pub threads: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CyclesPriceRequest {
+    /// In trillions of cycles, like `--cycles_price`.
+    pub cycles_price: f64,
+}
+
+/// The control and status endpoints of one engine:
+///
+/// - `GET /status`: block, deadline, hashrate and registration
+/// - `GET /threads`: what each thread is doing
+/// - `GET /submissions`: the latest submissions
+/// - `GET /metrics`: counters and gauges for Prometheus
+/// - `POST /pause`, `POST /resume`
+/// - `POST /threads` with `{"threads": 4}`, applies from the next round on
+/// - `POST /cycles_price` with `{"cycles_price": 0.5}`
+pub fn routes(
+    engine: MinerEngine,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_engine = warp::any().map(move || engine.clone());
+
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| warp::reply::json(&engine.status()));
+
+    let threads = warp::path!("threads")
+        .and(warp::get())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| warp::reply::json(&engine.thread_statuses()));
+
+    let submissions = warp::path!("submissions")
+        .and(warp::get())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| warp::reply::json(&engine.submissions()));
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| {
+            warp::reply::with_header(
+                engine.render_metrics(),
+                "content-type",
+                METRICS_CONTENT_TYPE,
+            )
+        });
+
+    let pause = warp::path!("pause")
+        .and(warp::post())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| {
+            engine.pause();
+            warp::reply::json(&engine.status())
+        });
+
+    let resume = warp::path!("resume")
+        .and(warp::post())
+        .and(with_engine.clone())
+        .map(|engine: MinerEngine| {
+            engine.resume();
+            warp::reply::json(&engine.status())
+        });
+
+    let set_threads = warp::path!("threads")
+        .and(warp::post())
+        .and(with_engine.clone())
+        .and(warp::body::json())
+        .map(|engine: MinerEngine, req: ThreadsRequest| {
+            if req.threads == 0 {
+                return bad_request("threads must be at least 1");
+            }
+            engine.set_max_threads(req.threads);
+            info!("Threads set to {} over the api", req.threads);
+            ok(&engine)
+        });
+
+    let set_cycles_price = warp::path!("cycles_price")
+        .and(warp::post())
+        .and(with_engine)
+        .and(warp::body::json())
+        .map(|engine: MinerEngine, req: CyclesPriceRequest| {
+            if !req.cycles_price.is_finite() || req.cycles_price < 0.0 {
+                return bad_request("cycles_price must be a number of at least 0");
+            }
+            engine.set_cycles_price(cycles_from_trillions(req.cycles_price));
+            info!("Cycles price set to {}T over the api", req.cycles_price);
+            ok(&engine)
+        });
+
+    status
+        .or(threads)
+        .or(submissions)
+        .or(metrics)
+        .or(pause)
+        .or(resume)
+        .or(set_threads)
+        .or(set_cycles_price)
+}
+
+fn ok(engine: &MinerEngine) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&engine.status()), StatusCode::OK)
+}
+
+fn bad_request(error: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&json!({ "error": error })),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+/// Binds [`routes`] to `addr` and returns the server, which runs until the
+/// process exits once spawned. Must be called within a tokio runtime.
+pub fn serve(engine: MinerEngine, addr: SocketAddr) -> Result<impl Future<Output = ()>, String> {
+    let (addr, server) = warp::serve(routes(engine))
+        .try_bind_ephemeral(addr)
+        .map_err(|e| format!("Error binding the control api to {}: {}", addr, e))?;
+    if !addr.ip().is_loopback() {
+        warn!(
+            "Control api on {} has no authentication, anyone who can reach it can pause the miner and change its threads and cycles price",
+            addr
+        );
+    }
+    info!("Control api listening on http://{}", addr);
+    Ok(server)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{routes, serve};
+    use crate::engine::MinerEngine;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn reports_status() {
+        let engine = MinerEngine::idle(Some(2));
+        let api = routes(engine.clone());
+
+        let res = warp::test::request().path("/status").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(status["registered"], false);
+        assert_eq!(status["max_threads"], 2);
+
+        let res = warp::test::request().path("/threads").reply(&api).await;
+        let threads: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(threads["1"], "Idle");
+
+        let res = warp::test::request().path("/submissions").reply(&api).await;
+        assert_eq!(res.body().as_ref(), b"[]");
+
+        let res = warp::test::request().path("/metrics").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body.contains("\ndod_miner_hashes_total 0\n"));
+        assert!(body.contains("\ndod_miner_threads 2\n"));
+    }
+
+    #[tokio::test]
+    async fn controls_engine() {
+        let engine = MinerEngine::idle(Some(2));
+        let api = routes(engine.clone());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/pause")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(engine.status().paused);
+
+        warp::test::request()
+            .method("POST")
+            .path("/threads")
+            .json(&serde_json::json!({ "threads": 5 }))
+            .reply(&api)
+            .await;
+        assert_eq!(engine.status().max_threads, 5);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/threads")
+            .json(&serde_json::json!({ "threads": 0 }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(engine.status().max_threads, 5);
+
+        warp::test::request()
+            .method("POST")
+            .path("/cycles_price")
+            .json(&serde_json::json!({ "cycles_price": 0.5 }))
+            .reply(&api)
+            .await;
+        assert_eq!(engine.config().cycles_price, 500_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn fails_to_bind_taken_port() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let res = serve(MinerEngine::idle(Some(2)), taken.local_addr().unwrap());
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::affinity::WorkerPlacement;
+use crate::miner::multi_run_v3;
+use crate::telemetry::RoundMonitor;
+use crate::threads::SharedThreads;
+use crate::types::MiningResultType;
+use dod_utils::bitwork::Bitwork;
+use dod_utils::mine::CancelToken;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// One block to mine.
+#[derive(Debug, Clone)]
+pub struct MiningJob {
+    pub bitwork: Bitwork,
+    pub remote_hash: Vec<u8>,
+    pub raw_pubkey: Vec<u8>,
+    pub dead_line: u128,
+    /// Threads to mine on, every available one if `None`. Backends that have no
+    /// use for it ignore it.
+    pub threads: Option<u32>,
+    pub height: Option<u64>,
+    /// Where the backend reports what each of its threads is doing, if it can.
+    pub workers: Option<SharedThreads>,
+    /// Draw progress bars on the terminal while mining, if the backend has any.
+    pub progress_bars: bool,
+    /// Cores and priority of the threads, for backends running on this machine.
+    pub placement: WorkerPlacement,
+}
+
+/// Something that can search for a bitwork solution, the cpu threads of this
+/// process or an external solver such as a subprocess or a remote worker.
+/// Each backend reports its solutions through its own `MiningResultType` variant.
+pub trait MiningBackend: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Starts mining `job` in the background and returns right away.
+    fn start(&self, job: MiningJob) -> MiningHandle;
+}
+
+/// The caller's side of a started job.
+pub struct MiningHandle {
+    cancel: CancelToken,
+    hashes: Arc<AtomicU64>,
+    result: oneshot::Receiver<Result<MiningResultType, String>>,
+}
+
+/// The backend's side of a started job.
+pub struct MiningReporter {
+    cancel: CancelToken,
+    hashes: Arc<AtomicU64>,
+    result: oneshot::Sender<Result<MiningResultType, String>>,
+}
+
+impl MiningHandle {
+    pub fn new() -> (MiningHandle, MiningReporter) {
+        let cancel = CancelToken::new();
+        let hashes = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = oneshot::channel();
+        (
+            MiningHandle {
+                cancel: cancel.clone(),
+                hashes: hashes.clone(),
+                result: rx,
+            },
+            MiningReporter {
+                cancel,
+                hashes,
+                result: tx,
+            },
+        )
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Hashes tried so far, as last reported by the backend.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Stays readable after `result` has consumed the handle.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.hashes.clone()
+    }
+
+    pub async fn result(self) -> Result<MiningResultType, String> {
+        self.result
+            .await
+            .unwrap_or_else(|_| Err("Mining backend stopped without a result".to_string()))
+    }
+}
+
+impl MiningReporter {
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.hashes.clone()
+    }
+
+    pub fn progress(&self, hashes: u64) {
+        self.hashes.store(hashes, Ordering::Relaxed);
+    }
+
+    pub fn finish(self, result: Result<MiningResultType, String>) {
+        let _ = self.result.send(result);
+    }
+}
+
+/// Mines on OS threads of this process with `multi_run_v3`, as many as the job
+/// asks for.
+#[derive(Debug, Clone, Default)]
+pub struct CpuBackend;
+
+impl MiningBackend for CpuBackend {
+    fn name(&self) -> String {
+        "cpu".to_string()
+    }
+
+    fn start(&self, job: MiningJob) -> MiningHandle {
+        let (handle, reporter) = MiningHandle::new();
+        tokio::spawn(async move {
+            let res = multi_run_v3(
+                job.bitwork,
+                job.remote_hash,
+                job.raw_pubkey,
+                job.threads,
+                job.dead_line,
+                reporter.cancel_token(),
+                RoundMonitor {
+                    counter: Some(reporter.counter()),
+                    workers: job.workers,
+                    height: job.height,
+                    progress_bars: job.progress_bars,
+                },
+                job.placement,
+            )
+            .await
+            .map(MiningResultType::Cpu);
+            reporter.finish(res);
+        });
+        handle
+    }
+}
+
+/// Starts jobs that never end, for tests of what drives a backend.
+#[cfg(test)]
+pub(crate) struct IdleBackend;
+
+#[cfg(test)]
+impl MiningBackend for IdleBackend {
+    fn name(&self) -> String {
+        "idle".to_string()
+    }
+
+    fn start(&self, _job: MiningJob) -> MiningHandle {
+        MiningHandle::new().0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::affinity::WorkerPlacement;
+    use crate::backend::{CpuBackend, MiningBackend, MiningHandle, MiningJob};
+    use crate::types::{MiningResult, MiningResultType};
+    use dod_utils::bitwork::Bitwork;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn reporter_delivers_result() {
+        let (handle, reporter) = MiningHandle::new();
+        reporter.progress(42);
+        assert_eq!(handle.hashes(), 42);
+
+        let res = MiningResultType::Cpu(MiningResult {
+            num_bytes: 7,
+            time: 1,
+            nonce: 2,
+        });
+        reporter.finish(Ok(res.clone()));
+        assert_eq!(handle.result().await, Ok(res));
+
+        let (handle, reporter) = MiningHandle::new();
+        drop(reporter);
+        assert!(handle.result().await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cpu_backend_cancels() {
+        let handle = CpuBackend.start(MiningJob {
+            placement: WorkerPlacement::default(),
+            bitwork: Bitwork {
+                pre: 32,
+                post_hex: "0".to_string(),
+            },
+            remote_hash: hex::decode(
+                "98799b250c911fe0df86cd59066e329d93bfb3d35fa57cdd3b243e2a8eec1b45",
+            )
+            .unwrap(),
+            raw_pubkey: hex::decode(
+                "02aa7360476d762b5a88df8db5ad2aabdf2656c3f64a5a9d3c0962541575916917",
+            )
+            .unwrap(),
+            dead_line: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+                + 60_000_000_000u128,
+            threads: Some(1),
+            height: None,
+            workers: None,
+            progress_bars: false,
+        });
+        // other tests may hold the round lock for a while before this job starts
+        let started = std::time::Instant::now();
+        while handle.hashes() == 0 && started.elapsed().as_secs() < 30 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(handle.hashes() > 0);
+
+        handle.cancel();
+        assert_eq!(handle.result().await, Err("Cancelled".to_string()));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::affinity::WorkerPlacement;
+use crate::miner::multi_run_v3;
+use crate::telemetry::{hashrate, RoundMonitor};
+use dod_cpu::threads::{get_available_threads, get_physical_threads};
+use dod_utils::bitwork::Bitwork;
+use dod_utils::mine::CancelToken;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A thread count is recommended over a larger one unless the larger one is
+/// faster by more than this share, extra threads mostly add heat past that.
+pub const BENCH_TOLERANCE: f64 = 0.02;
+
+/// Environment variable, usually set in `.env`, read when `--threads` is not given.
+pub const THREADS_ENV: &str = "DOD_THREADS";
+
+const BENCH_REMOTE_HASH: &str = "98799b250c911fe0df86cd59066e329d93bfb3d35fa57cdd3b243e2a8eec1b45";
+const BENCH_PUBKEY: &str = "02aa7360476d762b5a88df8db5ad2aabdf2656c3f64a5a9d3c0962541575916917";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub threads: u32,
+    pub hashes: u64,
+    pub elapsed: Duration,
+    pub rate: f64,
+}
+
+/// Thread counts worth trying: powers of two, the physical core count and every
+/// thread available to the process.
+pub fn bench_candidates(max_threads: u32) -> Vec<u32> {
+    let max_threads = max_threads.max(1);
+    let mut candidates = vec![max_threads, get_physical_threads().min(max_threads)];
+    let mut t = 1;
+    while t < max_threads {
+        candidates.push(t);
+        t *= 2;
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+    candidates
+}
+
+/// Runs a mining round on `threads` threads for `duration`, as the miner does,
+/// against a bitwork that will not be met, and counts the hashes it tried.
+pub async fn bench_threads(threads: u32, duration: Duration) -> BenchResult {
+    let counter = Arc::new(AtomicU64::new(0));
+    let dead_line = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+        + duration.as_nanos();
+
+    let started = Instant::now();
+    // a 32 nibble prefix is found all the same, its hashes count anyway
+    let _ = multi_run_v3(
+        Bitwork {
+            pre: 32,
+            post_hex: "0".to_string(),
+        },
+        hex::decode(BENCH_REMOTE_HASH).unwrap(),
+        hex::decode(BENCH_PUBKEY).unwrap(),
+        Some(threads),
+        dead_line,
+        CancelToken::new(),
+        RoundMonitor {
+            counter: Some(counter.clone()),
+            ..Default::default()
+        },
+        WorkerPlacement::default(),
+    )
+    .await;
+    let elapsed = started.elapsed();
+
+    let hashes = counter.load(Ordering::Relaxed);
+    BenchResult {
+        threads,
+        hashes,
+        elapsed,
+        rate: hashrate(hashes, elapsed),
+    }
+}
+
+pub async fn run_bench<F: FnMut(&BenchResult)>(
+    max_threads: Option<u32>,
+    duration: Duration,
+    mut on_result: F,
+) -> Vec<BenchResult> {
+    let mut results = vec![];
+    for threads in bench_candidates(max_threads.unwrap_or_else(get_available_threads)) {
+        let res = bench_threads(threads, duration).await;
+        on_result(&res);
+        results.push(res);
+    }
+    results
+}
+
+/// The fewest threads within [`BENCH_TOLERANCE`] of the best hashrate measured.
+pub fn recommend_threads(results: &[BenchResult]) -> Option<u32> {
+    let best = results.iter().map(|r| r.rate).fold(0.0, f64::max);
+    results
+        .iter()
+        .filter(|r| r.rate >= best * (1.0 - BENCH_TOLERANCE))
+        .map(|r| r.threads)
+        .min()
+}
+
+/// Sets [`THREADS_ENV`] in the dotenv file at `path`, keeping the other entries.
+pub fn save_threads(path: &str, threads: u32) -> Result<(), String> {
+    let existing = fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|l| !l.trim_start().starts_with(&format!("{}=", THREADS_ENV)))
+        .map(|l| l.to_string())
+        .collect();
+    lines.push(format!("{}={}", THREADS_ENV, threads));
+    fs::write(path, lines.join("\n") + "\n").map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bench::{
+        bench_candidates, bench_threads, recommend_threads, save_threads, BenchResult,
+    };
+    use std::time::Duration;
+
+    fn result(threads: u32, rate: f64) -> BenchResult {
+        BenchResult {
+            threads,
+            hashes: 0,
+            elapsed: Duration::from_secs(1),
+            rate,
+        }
+    }
+
+    #[test]
+    fn candidates() {
+        let c = bench_candidates(12);
+        assert_eq!(c.first(), Some(&1));
+        assert_eq!(c.last(), Some(&12));
+        assert!(c.contains(&8));
+        assert!(c.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(bench_candidates(0), vec![1]);
+    }
+
+    #[test]
+    fn recommends_fewest_threads_near_best() {
+        let results = vec![
+            result(1, 100.0),
+            result(2, 195.0),
+            result(4, 300.0),
+            result(8, 302.0),
+        ];
+        assert_eq!(recommend_threads(&results), Some(4));
+        assert_eq!(recommend_threads(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn bench_counts_hashes() {
+        let res = bench_threads(1, Duration::from_millis(200)).await;
+        assert!(res.hashes > 0);
+        assert!(res.rate > 0.0);
+    }
+
+    #[test]
+    fn saves_threads_to_dotenv() {
+        let path = std::env::temp_dir().join(format!("dod_bench_{}.env", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "FOO=1\nDOD_THREADS=3\n").unwrap();
+
+        save_threads(path, 6).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "FOO=1\nDOD_THREADS=6\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
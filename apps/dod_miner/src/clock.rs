@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// A warning is logged when the local clock is further off IC time than this.
+pub const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(1);
+/// How far the certified time may trail real time. It reads as the local clock
+/// being that much ahead, so drift that way only warns past this on top.
+pub const CERTIFIED_TIME_LAG: Duration = Duration::from_secs(3);
+/// How often the offset is measured again.
+pub const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Local unix nanoseconds.
+pub fn local_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+/// How far IC time is ahead of the local clock, in nanoseconds. Block times and
+/// deadlines from the canister are IC time, while the mining threads check their
+/// deadline against the local clock.
+#[derive(Debug, Clone, Default)]
+pub struct ClockOffset {
+    nanos: Arc<AtomicI64>,
+}
+
+impl ClockOffset {
+    pub fn nanos(&self) -> i64 {
+        self.nanos.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, nanos: i64) {
+        self.nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    /// The current IC time as estimated from the local clock.
+    pub fn ic_now(&self) -> u128 {
+        self.to_ic(local_nanos())
+    }
+
+    pub fn to_ic(&self, local: u128) -> u128 {
+        (local as i128 + self.nanos() as i128).max(0) as u128
+    }
+
+    pub fn to_local(&self, ic: u128) -> u128 {
+        (ic as i128 - self.nanos() as i128).max(0) as u128
+    }
+
+    /// `to_local` for a deadline. A negative offset is as likely the lag of the
+    /// certified time as a local clock running ahead, so a deadline is only ever
+    /// moved earlier, never past the uncorrected one.
+    pub fn deadline_to_local(&self, ic: u128) -> u128 {
+        self.to_local(ic).min(ic)
+    }
+
+    /// Whether the local clock is more than `drift` behind IC time, or more than
+    /// `drift` and `lag` ahead of it.
+    pub fn exceeds(&self, drift: Duration, lag: Duration) -> bool {
+        let nanos = self.nanos() as i128;
+        nanos > drift.as_nanos() as i128 || -nanos > (drift + lag).as_nanos() as i128
+    }
+}
+
+/// Offset of `ic_time` read between local times `sent` and `received`, taking it
+/// as read halfway through the call.
+pub fn estimate_offset(ic_time: u64, sent: u128, received: u128) -> i64 {
+    let local = sent + received.saturating_sub(sent) / 2;
+    (ic_time as i128 - local as i128) as i64
+}
+
+/// Unsigned LEB128, the encoding of `time` in the certified state tree.
+pub fn decode_leb128(bytes: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, b) in bytes.iter().enumerate() {
+        if i >= 10 {
+            return None;
+        }
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::{
+        decode_leb128, estimate_offset, ClockOffset, CERTIFIED_TIME_LAG, MAX_CLOCK_DRIFT,
+    };
+
+    #[test]
+    fn converts_between_clocks() {
+        let clock = ClockOffset::default();
+        assert_eq!(clock.to_local(1_000), 1_000);
+
+        // IC time is 2s ahead
+        clock.set(estimate_offset(
+            12_000_000_000,
+            9_000_000_000,
+            11_000_000_000,
+        ));
+        assert_eq!(clock.nanos(), 2_000_000_000);
+        assert_eq!(clock.to_local(12_000_000_000), 10_000_000_000);
+        assert_eq!(clock.to_ic(10_000_000_000), 12_000_000_000);
+        assert!(clock.exceeds(MAX_CLOCK_DRIFT, CERTIFIED_TIME_LAG));
+
+        clock.set(-500_000_000);
+        assert!(!clock.exceeds(MAX_CLOCK_DRIFT, CERTIFIED_TIME_LAG));
+        // within the lag of the certified time
+        clock.set(-3_500_000_000);
+        assert!(!clock.exceeds(MAX_CLOCK_DRIFT, CERTIFIED_TIME_LAG));
+        clock.set(-5_000_000_000);
+        assert!(clock.exceeds(MAX_CLOCK_DRIFT, CERTIFIED_TIME_LAG));
+    }
+
+    #[test]
+    fn lagging_ic_time_never_delays_deadline() {
+        let clock = ClockOffset::default();
+        let dead_line = 100_000_000_000u128;
+
+        // the certified time trails the correct local clock by 2s
+        clock.set(estimate_offset(
+            8_000_000_000,
+            9_000_000_000,
+            11_000_000_000,
+        ));
+        assert_eq!(clock.nanos(), -2_000_000_000);
+        assert_eq!(clock.deadline_to_local(dead_line), dead_line);
+
+        // a local clock behind IC time still gives up earlier
+        clock.set(2_000_000_000);
+        assert_eq!(
+            clock.deadline_to_local(dead_line),
+            dead_line - 2_000_000_000
+        );
+    }
+
+    #[test]
+    fn decodes_leb128() {
+        assert_eq!(decode_leb128(&[0x00]), Some(0));
+        assert_eq!(decode_leb128(&[0xe5, 0x8e, 0x26]), Some(624_485));
+        assert_eq!(decode_leb128(&[0x80]), None);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::affinity::{numa_node_cpus, parse_cpu_list, WorkerPlacement, WorkerPriority};
+use crate::engine::{cycles_from_trillions, MinerConfig, MinerEngine};
+use crate::governor::GovernorConfig;
+use crate::polling::PollConfig;
+use crate::schedule::{Schedule, WindowConfig};
+use dod_cpu::threads::get_affinity_cpus;
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+
+/// Read when `--config` is not given, and skipped if it does not exist.
+pub const DEFAULT_CONFIG_FILE: &str = "config/dod_miner.toml";
+pub const DEFAULT_LOG_CONFIG: &str = "config/log4rs.yaml";
+/// How often the config file is checked for changes.
+pub const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Settings of one deployment. Everything is optional, what a profile leaves out
+/// comes from the command line or the built-in defaults.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// `ic` or `local`.
+    pub ic_network: Option<String>,
+    pub ic_url: Option<String>,
+    pub dod_canister: Option<String>,
+    pub siwb_canister: Option<String>,
+    /// Seconds before the next block is due that a round gives up.
+    pub deadline_diff: Option<f64>,
+    /// Seconds, see [`PollConfig`].
+    pub poll_interval: Option<f64>,
+    pub near_poll_interval: Option<f64>,
+    pub threads: Option<u32>,
+    /// In trillions of cycles.
+    pub cycles_price: Option<f64>,
+    /// log4rs config file.
+    pub log_config: Option<String>,
+    /// Mining windows, mining never stops without them.
+    pub schedule: Option<Vec<WindowConfig>>,
+    /// Thread throttling on temperature and load, off without it.
+    pub governor: Option<GovernorConfig>,
+    /// Cpus to pin the mining threads to, as in `0-3,8`.
+    pub cores: Option<String>,
+    /// Pin the mining threads to the cpus of this NUMA node instead.
+    pub numa_node: Option<u32>,
+    /// Run the mining threads at `SCHED_IDLE`.
+    pub sched_idle: Option<bool>,
+    /// Nice level of the mining threads.
+    pub nice: Option<i32>,
+}
+
+/// The config file: named profiles and the one used when `--profile` is not given.
+///
+/// ```toml
+/// default_profile = "mainnet"
+///
+/// [profiles.mainnet]
+/// ic_network = "ic"
+/// dod_canister = "tmhkz-dyaaa-aaaah-aedeq-cai"
+///
+/// [profiles.local]
+/// ic_network = "local"
+/// ic_url = "http://127.0.0.1:8080"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+}
+
+impl ConfigFile {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// `name`, or the default profile. No profile at all is fine as long as
+    /// none was asked for.
+    pub fn profile(&self, name: Option<&str>) -> Result<ProfileConfig, String> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self.profiles.get(name).cloned().ok_or_else(|| {
+                format!(
+                    "No profile {} in the config, found: {}",
+                    name,
+                    self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            }),
+            None => Ok(ProfileConfig::default()),
+        }
+    }
+}
+
+/// The profile `name` from the file at `path`. A missing file is only an error
+/// if it was asked for with `required` or a profile was named.
+pub fn load_profile(
+    path: &str,
+    name: Option<&str>,
+    required: bool,
+) -> Result<ProfileConfig, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => ConfigFile::parse(&s)
+            .map_err(|e| format!("Error reading {}: {}", path, e))?
+            .profile(name),
+        Err(_) if !required && name.is_none() => Ok(ProfileConfig::default()),
+        Err(e) => Err(format!("Error reading {}: {}", path, e)),
+    }
+}
+
+/// Where the settings come from, kept to read them again on a reload.
+#[derive(Debug, Clone)]
+pub struct SettingsSource {
+    pub path: String,
+    pub profile: Option<String>,
+    /// The file was asked for, see [`load_profile`].
+    pub required: bool,
+    /// Laid over the profile, the environment and command line.
+    pub overrides: ProfileConfig,
+}
+
+impl SettingsSource {
+    pub fn load(&self) -> Result<ProfileConfig, String> {
+        Ok(
+            load_profile(&self.path, self.profile.as_deref(), self.required)?
+                .merge(self.overrides.clone()),
+        )
+    }
+
+    /// Modification time of the file, `None` while it does not exist.
+    pub fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+}
+
+/// Reloads the settings of `engine` whenever the file of `source` changes or
+/// the process gets SIGHUP, until the process exits. Settings that do not parse
+/// are logged and the running ones kept.
+pub async fn watch(engine: MinerEngine, source: SettingsSource, wif: String) {
+    let hangup = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let hangup = hangup.clone();
+        let mut signal = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(async move {
+            while signal.recv().await.is_some() {
+                hangup.notify_one();
+            }
+        });
+    }
+
+    let mut modified = source.modified();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(RELOAD_CHECK_INTERVAL) => {
+                let now = source.modified();
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                info!("{} changed, reloading settings", source.path);
+            }
+            _ = hangup.notified() => {
+                modified = source.modified();
+                info!("Got SIGHUP, reloading settings");
+            }
+        }
+
+        match source.load().and_then(|s| s.miner_config(wif.clone())) {
+            Ok(config) => {
+                let changed = engine.reload(config);
+                if changed.is_empty() {
+                    info!("No settings changed");
+                } else {
+                    info!(
+                        "Reloaded {}, applied from the next round on",
+                        changed.join(", ")
+                    );
+                }
+            }
+            Err(e) => error!("Keeping the running settings: {}", e),
+        }
+    }
+}
+
+fn secs(name: &str, value: f64) -> Result<Duration, String> {
+    if value.is_finite() && value > 0.0 {
+        Ok(Duration::from_secs_f64(value))
+    } else {
+        Err(format!("{} must be a positive number of seconds", name))
+    }
+}
+
+impl ProfileConfig {
+    /// `over` wins wherever it sets a field.
+    pub fn merge(self, over: ProfileConfig) -> ProfileConfig {
+        ProfileConfig {
+            ic_network: over.ic_network.or(self.ic_network),
+            ic_url: over.ic_url.or(self.ic_url),
+            dod_canister: over.dod_canister.or(self.dod_canister),
+            siwb_canister: over.siwb_canister.or(self.siwb_canister),
+            deadline_diff: over.deadline_diff.or(self.deadline_diff),
+            poll_interval: over.poll_interval.or(self.poll_interval),
+            near_poll_interval: over.near_poll_interval.or(self.near_poll_interval),
+            threads: over.threads.or(self.threads),
+            cycles_price: over.cycles_price.or(self.cycles_price),
+            log_config: over.log_config.or(self.log_config),
+            schedule: over.schedule.or(self.schedule),
+            governor: over.governor.or(self.governor),
+            cores: over.cores.or(self.cores),
+            numa_node: over.numa_node.or(self.numa_node),
+            sched_idle: over.sched_idle.or(self.sched_idle),
+            nice: over.nice.or(self.nice),
+        }
+    }
+
+    pub fn log_config(&self) -> &str {
+        self.log_config.as_deref().unwrap_or(DEFAULT_LOG_CONFIG)
+    }
+
+    pub fn placement(&self) -> Result<WorkerPlacement, String> {
+        let cores = match (self.cores.as_deref(), self.numa_node) {
+            (Some(_), Some(_)) => return Err("Set either cores or numa_node".to_string()),
+            (Some(cores), None) => Some(parse_cpu_list(cores)?),
+            (None, Some(node)) => Some(numa_node_cpus(node)?),
+            (None, None) => None,
+        };
+        let priority = match (self.sched_idle.unwrap_or(false), self.nice) {
+            (true, Some(_)) => return Err("Set either sched_idle or nice".to_string()),
+            (true, None) => WorkerPriority::Idle,
+            (false, Some(nice)) if !(-20..=19).contains(&nice) => {
+                return Err("nice must be between -20 and 19".to_string())
+            }
+            (false, Some(nice)) => WorkerPriority::Nice(nice),
+            (false, None) => WorkerPriority::Normal,
+        };
+        Ok(WorkerPlacement { cores, priority })
+    }
+
+    pub fn miner_config(&self, wif: String) -> Result<MinerConfig, String> {
+        let cycles_price = self
+            .cycles_price
+            .ok_or_else(|| "No cycles price set".to_string())?;
+        if !cycles_price.is_finite() || cycles_price < 0.0 {
+            return Err("cycles_price must be a number of at least 0".to_string());
+        }
+
+        let mut config = MinerConfig::new(wif, cycles_from_trillions(cycles_price));
+        if let Some(ic_network) = self.ic_network.clone() {
+            config.ic_network = ic_network;
+        }
+        config.ic_url = self.ic_url.clone();
+        if let Some(dod_canister) = self.dod_canister.clone() {
+            config.dod_canister = dod_canister;
+        }
+        if let Some(siwb_canister) = self.siwb_canister.clone() {
+            config.siwb_canister = siwb_canister;
+        }
+        if let Some(deadline_diff) = self.deadline_diff {
+            config.deadline_diff = secs("deadline_diff", deadline_diff)?.as_nanos() as u64;
+        }
+        let mut poll = PollConfig::default();
+        if let Some(interval) = self.poll_interval {
+            poll.interval = secs("poll_interval", interval)?;
+        }
+        if let Some(near_interval) = self.near_poll_interval {
+            poll.near_interval = secs("near_poll_interval", near_interval)?;
+        }
+        config.poll = poll;
+        if self.threads == Some(0) {
+            return Err("threads must be at least 1".to_string());
+        }
+        config.threads = self.threads;
+        if let Some(windows) = self.schedule.as_ref() {
+            config.schedule = Schedule::new(windows)?;
+        }
+        if let Some(governor) = self.governor.as_ref() {
+            governor.validate()?;
+        }
+        config.governor = self.governor.clone();
+        config.placement = self.placement()?;
+        if let Some(allowed) = get_affinity_cpus() {
+            config.placement.check_cores(&allowed)?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::affinity::{WorkerPlacement, WorkerPriority};
+    use crate::config::{ConfigFile, ProfileConfig};
+    use crate::engine::DEFAULT_DOD_CANISTER;
+    use std::time::Duration;
+
+    const FILE: &str = r#"
+default_profile = "mainnet"
+
+[profiles.mainnet]
+ic_network = "ic"
+cycles_price = 0.5
+
+[profiles.staging]
+ic_network = "ic"
+dod_canister = "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+deadline_diff = 8
+threads = 4
+
+[[profiles.staging.schedule]]
+days = ["sat", "sun"]
+from = "08:00"
+to = "20:00"
+threads = 2
+"#;
+
+    #[test]
+    fn picks_profiles() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        assert_eq!(file.profile(None).unwrap().cycles_price, Some(0.5));
+        assert_eq!(file.profile(Some("staging")).unwrap().threads, Some(4));
+        assert!(file.profile(Some("local")).is_err());
+        assert!(ConfigFile::parse("[profiles.mainnet]\ncanister = \"x\"").is_err());
+        assert_eq!(
+            ConfigFile::default().profile(None),
+            Ok(ProfileConfig::default())
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        let cli = ProfileConfig {
+            cycles_price: Some(1.0),
+            threads: Some(2),
+            ..Default::default()
+        };
+
+        let config = file
+            .profile(Some("staging"))
+            .unwrap()
+            .merge(cli)
+            .miner_config("wif".to_string())
+            .unwrap();
+        assert_eq!(config.cycles_price, 1_000_000_000_000);
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.dod_canister, "bkyz2-fmaaa-aaaaa-qaaaq-cai");
+        assert_eq!(config.deadline_diff, 8_000_000_000);
+        assert_eq!(config.poll.interval, Duration::from_secs(5));
+        assert_eq!(config.schedule.windows.len(), 1);
+        assert_eq!(config.schedule.windows[0].threads, Some(2));
+
+        let config = file
+            .profile(None)
+            .unwrap()
+            .miner_config("wif".to_string())
+            .unwrap();
+        assert_eq!(config.dod_canister, DEFAULT_DOD_CANISTER);
+        assert!(ProfileConfig::default()
+            .miner_config("wif".to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn worker_placement() {
+        let settings = ProfileConfig {
+            cores: Some("0-1,4".to_string()),
+            nice: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.placement(),
+            Ok(WorkerPlacement {
+                cores: Some(vec![0, 1, 4]),
+                priority: WorkerPriority::Nice(10),
+            })
+        );
+
+        let both = ProfileConfig {
+            sched_idle: Some(true),
+            ..settings.clone()
+        };
+        assert!(both.placement().is_err());
+        let numa = ProfileConfig {
+            numa_node: Some(0),
+            ..settings
+        };
+        assert!(numa.placement().is_err());
+    }
+
+    #[test]
+    fn shipped_config_parses() {
+        let file = ConfigFile::parse(include_str!("../../../config/dod_miner.toml")).unwrap();
+        let mainnet = file.profile(None).unwrap();
+        assert_eq!(mainnet.dod_canister.as_deref(), Some(DEFAULT_DOD_CANISTER));
+        let local = file.profile(Some("local")).unwrap();
+        assert_eq!(local.ic_network.as_deref(), Some("local"));
+        // left to IC_REF_PORT
+        assert_eq!(local.ic_url, None);
+    }
+}
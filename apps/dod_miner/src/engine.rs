@@ -0,0 +1,1298 @@
+use crate::affinity::WorkerPlacement;
+use crate::backend::{CpuBackend, MiningBackend, MiningJob};
+use crate::clock::{
+    estimate_offset, local_nanos, ClockOffset, CERTIFIED_TIME_LAG, CLOCK_SYNC_INTERVAL,
+    MAX_CLOCK_DRIFT,
+};
+use crate::fetcher::{get_p2tr_from_wif, FetcherService};
+use crate::governor::GovernorConfig;
+use crate::metrics::Metrics;
+use crate::polling::PollConfig;
+use crate::schedule::{Schedule, SCHEDULE_CHECK_INTERVAL};
+use crate::telemetry::hashrate;
+use crate::threads::{SharedThreads, ThreadsManager};
+use crate::types::{BlockData, MiningResultType, ThreadStatus};
+use candid::Principal;
+use dod_utils::bitwork::Bitwork;
+use dod_utils::mine::CancelToken;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_SIWB_CANISTER: &str = "mwm4a-eiaaa-aaaah-aebnq-cai";
+pub const DEFAULT_DOD_CANISTER: &str = "tmhkz-dyaaa-aaaah-aedeq-cai";
+pub const DEFAULT_IC_NETWORK: &str = "ic";
+pub const DEFAULT_DEADLINE_DIFF: u64 = 5_000_000_000;
+/// How long shutdown waits for `miner_submit_hash` calls still in flight.
+pub const SUBMIT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Where solutions still unsubmitted at shutdown are appended, one json per line.
+pub const UNSUBMITTED_FILE: &str = "unsubmitted_solutions.jsonl";
+
+const EVENT_CAPACITY: usize = 256;
+/// Submissions kept for `submissions`, oldest dropped first.
+const SUBMISSION_HISTORY: usize = 20;
+/// Rounds kept for `rounds`, oldest dropped first.
+const ROUND_HISTORY: usize = 20;
+
+/// Cycles price as given on the command line, in trillions of cycles.
+pub fn cycles_from_trillions(trillions: f64) -> u128 {
+    (trillions * u128::pow(10, 12) as f64).round() as u128
+}
+
+#[derive(Debug, Clone)]
+pub struct MinerConfig {
+    pub wif: String,
+    /// Cycles price in whole cycles, not trillions.
+    pub cycles_price: u128,
+    /// How long before `next_block_time` a round gives up, in nanoseconds.
+    pub deadline_diff: u64,
+    /// How often the canister is asked for the last block.
+    pub poll: PollConfig,
+    pub threads: Option<u32>,
+    pub dod_canister: String,
+    pub siwb_canister: String,
+    pub ic_network: String,
+    /// Replica url, `https://icp-api.io` on `ic` and `IC_REF_PORT` on localhost otherwise.
+    pub ic_url: Option<String>,
+    /// Draw the per-thread progress bars of each round on the terminal.
+    pub progress_bars: bool,
+    /// When to mine, always if it has no windows.
+    pub schedule: Schedule,
+    /// Throttles the threads on temperature and load, see [`crate::governor`].
+    pub governor: Option<GovernorConfig>,
+    /// Cores and priority of the mining threads.
+    pub placement: WorkerPlacement,
+}
+
+impl MinerConfig {
+    pub fn new(wif: String, cycles_price: u128) -> Self {
+        MinerConfig {
+            wif,
+            cycles_price,
+            deadline_diff: DEFAULT_DEADLINE_DIFF,
+            poll: PollConfig::default(),
+            threads: None,
+            dod_canister: DEFAULT_DOD_CANISTER.to_string(),
+            siwb_canister: DEFAULT_SIWB_CANISTER.to_string(),
+            ic_network: DEFAULT_IC_NETWORK.to_string(),
+            ic_url: None,
+            progress_bars: true,
+            schedule: Schedule::default(),
+            governor: None,
+            placement: WorkerPlacement::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum MinerEvent {
+    BlockFetched {
+        height: u64,
+        bitwork: Bitwork,
+    },
+    RoundStarted {
+        height: u64,
+        threads: u32,
+    },
+    SolutionFound {
+        height: u64,
+        result: MiningResultType,
+    },
+    RoundEnded {
+        height: u64,
+        hashes: u64,
+    },
+    Submitted {
+        height: u64,
+        block_height: u64,
+    },
+    Paused,
+    Resumed,
+    Stopped,
+    Error(String),
+}
+
+/// The block the engine is mining right now.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoundInfo {
+    pub height: u64,
+    pub bitwork: Bitwork,
+    pub remote_hash: String,
+    /// Unix nanoseconds the canister expects the next block at.
+    pub next_block_time: u64,
+    /// Unix nanoseconds the round gives up at.
+    pub dead_line: u128,
+    pub threads: u32,
+    /// Unix seconds the round started at.
+    pub started_at: u64,
+}
+
+struct FetchedBlock {
+    height: u64,
+    hash: Vec<u8>,
+    bitwork: Bitwork,
+    next_block_time: u64,
+    dead_line: u128,
+}
+
+struct ActiveRound {
+    info: RoundInfo,
+    hashes: Arc<AtomicU64>,
+    started: Instant,
+}
+
+/// How submitting the solution for a block went.
+#[derive(Debug, Clone, Serialize)]
+pub struct Submission {
+    pub height: u64,
+    pub block_height: Option<u64>,
+    pub error: Option<String>,
+    /// Unix seconds the canister answered at.
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub enum RoundOutcome {
+    Found,
+    Expired,
+    Cancelled,
+    Failed(String),
+}
+
+/// A round that is over.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoundRecord {
+    pub height: u64,
+    pub hashes: u64,
+    pub outcome: RoundOutcome,
+    /// Cycles price the solution is submitted at.
+    pub cycles_price: u128,
+    /// Unix seconds the round ended at.
+    pub ended_at: u64,
+    /// Filled in once the canister answers.
+    pub submission: Option<Submission>,
+}
+
+/// A solution found but not yet answered for by the canister.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PendingSubmission {
+    pub height: u64,
+    pub remote_hash: String,
+    pub result: MiningResultType,
+    /// Unix seconds the solution was found at.
+    pub found_at: u64,
+}
+
+/// Appends `pending` to the file at `path`, one json per line.
+pub fn save_pending(path: &str, pending: &[PendingSubmission]) -> Result<(), String> {
+    let mut lines = String::new();
+    for p in pending {
+        lines += &serde_json::to_string(p).map_err(|e| e.to_string())?;
+        lines.push('\n');
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    file.write_all(lines.as_bytes()).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EngineStatus {
+    pub running: bool,
+    pub paused: bool,
+    /// Outside every window of the schedule, waiting for the next one.
+    pub off_schedule: bool,
+    pub stopped: bool,
+    pub registered: bool,
+    pub latest_block: Option<u64>,
+    pub round: Option<RoundInfo>,
+    /// Hashes tried in the current round.
+    pub hashes: u64,
+    /// Hashes per second over the current round.
+    pub hashrate: f64,
+    pub max_threads: u32,
+    /// Set by the governor while it holds the threads under `max_threads`.
+    pub thread_limit: Option<u32>,
+    pub cycles_price: u128,
+    pub btc_address: Option<String>,
+    /// How far IC time is ahead of the local clock, in nanoseconds.
+    pub clock_offset: i64,
+}
+
+/// A miner: the canister connection, the threads it may use, the block it is on
+/// and whether it is mining. Cloning gives another handle to the same miner, and
+/// several engines can run side by side in one process.
+#[derive(Clone)]
+pub struct MinerEngine {
+    inner: Arc<EngineInner>,
+}
+
+struct EngineInner {
+    config: Mutex<MinerConfig>,
+    /// The settings as last read, so a reload only applies what changed in them
+    /// and leaves what was set over the api alone.
+    loaded: Mutex<MinerConfig>,
+    backend: Arc<dyn MiningBackend>,
+    fetcher: Mutex<FetcherService>,
+    threads: SharedThreads,
+    /// Thread count waiting for the running round to end.
+    pending_threads: Mutex<Option<u32>>,
+    thread_limit: Mutex<Option<u32>>,
+    latest_block: Mutex<Option<u64>>,
+    next_block_time: Mutex<Option<u64>>,
+    poll_errors: AtomicU32,
+    clock: ClockOffset,
+    clock_synced: Mutex<Option<Instant>>,
+    round: Mutex<Option<ActiveRound>>,
+    submissions: Mutex<VecDeque<Submission>>,
+    rounds: Mutex<VecDeque<RoundRecord>>,
+    metrics: Arc<Metrics>,
+    pending: Mutex<BTreeMap<u64, PendingSubmission>>,
+    next_pending: AtomicU64,
+    drained: Notify,
+    address: Mutex<Option<(String, String)>>,
+    cancel: Mutex<Option<CancelToken>>,
+    running: AtomicBool,
+    paused: AtomicBool,
+    off_schedule: AtomicBool,
+    stopped: AtomicBool,
+    wake: Notify,
+    events: broadcast::Sender<MinerEvent>,
+}
+
+impl MinerEngine {
+    pub fn new(config: MinerConfig, backend: Arc<dyn MiningBackend>) -> Result<Self, String> {
+        let mut fetcher = FetcherService::default();
+        fetcher.set_dod_canister(
+            Principal::from_text(&config.dod_canister).map_err(|e| e.to_string())?,
+        );
+        fetcher.set_siwb_canister(
+            Principal::from_text(&config.siwb_canister).map_err(|e| e.to_string())?,
+        );
+        fetcher.set_ic_network(Some(config.ic_network.clone()));
+        fetcher.set_ic_url(config.ic_url.clone());
+        let metrics = Arc::new(Metrics::default());
+        fetcher.set_metrics(metrics.clone());
+
+        let mut threads = ThreadsManager::default();
+        threads.set_max_threads(configured_threads(&config));
+
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Ok(MinerEngine {
+            inner: Arc::new(EngineInner {
+                loaded: Mutex::new(config.clone()),
+                config: Mutex::new(config),
+                backend,
+                fetcher: Mutex::new(fetcher),
+                threads: Arc::new(Mutex::new(threads)),
+                pending_threads: Mutex::new(None),
+                thread_limit: Mutex::new(None),
+                latest_block: Mutex::new(None),
+                next_block_time: Mutex::new(None),
+                poll_errors: AtomicU32::new(0),
+                clock: ClockOffset::default(),
+                clock_synced: Mutex::new(None),
+                round: Mutex::new(None),
+                submissions: Mutex::new(VecDeque::new()),
+                rounds: Mutex::new(VecDeque::new()),
+                metrics,
+                pending: Mutex::new(BTreeMap::new()),
+                next_pending: AtomicU64::new(0),
+                drained: Notify::new(),
+                address: Mutex::new(None),
+                cancel: Mutex::new(None),
+                running: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                off_schedule: AtomicBool::new(false),
+                stopped: AtomicBool::new(false),
+                wake: Notify::new(),
+                events,
+            }),
+        })
+    }
+
+    /// An engine mining on the cpu threads of this process.
+    pub fn with_cpu(config: MinerConfig) -> Result<Self, String> {
+        MinerEngine::new(config, Arc::new(CpuBackend))
+    }
+
+    pub fn config(&self) -> MinerConfig {
+        self.inner.config.lock().unwrap().clone()
+    }
+
+    /// Applies to the next submission.
+    pub fn set_cycles_price(&self, cycles_price: u128) {
+        self.inner.config.lock().unwrap().cycles_price = cycles_price;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MinerEvent> {
+        self.inner.events.subscribe()
+    }
+
+    fn emit(&self, event: MinerEvent) {
+        // no subscribers is fine
+        let _ = self.inner.events.send(event);
+    }
+
+    pub fn status(&self) -> EngineStatus {
+        let btc_address = self
+            .inner
+            .address
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|a| a.0.clone());
+        let (round, hashes, rate) = match self.inner.round.lock().unwrap().as_ref() {
+            Some(r) => {
+                let hashes = r.hashes.load(Ordering::Relaxed);
+                (
+                    Some(r.info.clone()),
+                    hashes,
+                    hashrate(hashes, r.started.elapsed()),
+                )
+            }
+            None => (None, 0, 0.0),
+        };
+        EngineStatus {
+            running: self.inner.running.load(Ordering::Acquire),
+            paused: self.inner.paused.load(Ordering::Acquire),
+            off_schedule: self.inner.off_schedule.load(Ordering::Acquire),
+            stopped: self.inner.stopped.load(Ordering::Acquire),
+            registered: btc_address.is_some(),
+            latest_block: *self.inner.latest_block.lock().unwrap(),
+            round,
+            hashes,
+            hashrate: rate,
+            max_threads: self.inner.threads.lock().unwrap().max_threads,
+            thread_limit: *self.inner.thread_limit.lock().unwrap(),
+            cycles_price: self.inner.config.lock().unwrap().cycles_price,
+            btc_address,
+            clock_offset: self.inner.clock.nanos(),
+        }
+    }
+
+    /// The current IC time, from the local clock corrected by the measured offset.
+    pub fn ic_now(&self) -> u128 {
+        self.inner.clock.ic_now()
+    }
+
+    /// Measures how far the local clock is off IC time, and warns past
+    /// [`MAX_CLOCK_DRIFT`], allowing for [`CERTIFIED_TIME_LAG`] when the local
+    /// clock reads ahead. Deadlines are only corrected to be earlier, see
+    /// [`ClockOffset::deadline_to_local`].
+    pub async fn sync_clock(&self) -> Result<i64, String> {
+        let sent = local_nanos();
+        let ic_time = self.fetcher().get_ic_time().await?;
+        let offset = estimate_offset(ic_time, sent, local_nanos());
+
+        self.inner.clock.set(offset);
+        *self.inner.clock_synced.lock().unwrap() = Some(Instant::now());
+        if self
+            .inner
+            .clock
+            .exceeds(MAX_CLOCK_DRIFT, CERTIFIED_TIME_LAG)
+        {
+            warn!(
+                "Local clock is {:+.3}s ahead of IC time, check its time sync",
+                offset as f64 / -1e9
+            );
+        }
+        Ok(offset)
+    }
+
+    async fn sync_clock_if_due(&self) {
+        let due = self
+            .inner
+            .clock_synced
+            .lock()
+            .unwrap()
+            .is_none_or(|t| t.elapsed() >= CLOCK_SYNC_INTERVAL);
+        if due && self.inner.address.lock().unwrap().is_some() {
+            if let Err(e) = self.sync_clock().await {
+                info!("Failed to measure the clock offset: {}", e);
+            }
+        }
+    }
+
+    /// All metrics of the engine in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let status = self.status();
+        let round = self.inner.round.lock().unwrap();
+        let round_hashes = round
+            .as_ref()
+            .map_or(0, |r| r.hashes.load(Ordering::Relaxed));
+        let gauges = [
+            (
+                "dod_miner_hashrate",
+                "Hashes per second over the current round.",
+                status.hashrate,
+            ),
+            (
+                "dod_miner_latest_block",
+                "Height of the latest block seen.",
+                status.latest_block.map_or(0.0, |b| b as f64),
+            ),
+            (
+                "dod_miner_running",
+                "1 while a round is running.",
+                status.running as u8 as f64,
+            ),
+            (
+                "dod_miner_paused",
+                "1 while mining is paused.",
+                status.paused as u8 as f64,
+            ),
+            (
+                "dod_miner_off_schedule",
+                "1 while outside every mining window.",
+                status.off_schedule as u8 as f64,
+            ),
+            (
+                "dod_miner_registered",
+                "1 once registered as a miner.",
+                status.registered as u8 as f64,
+            ),
+            (
+                "dod_miner_threads",
+                "Threads a round mines on.",
+                status.max_threads as f64,
+            ),
+            (
+                "dod_miner_clock_offset_seconds",
+                "How far IC time is ahead of the local clock.",
+                status.clock_offset as f64 / 1e9,
+            ),
+        ];
+        self.inner.metrics.render(round_hashes, &gauges)
+    }
+
+    /// The latest rounds, oldest first.
+    pub fn rounds(&self) -> Vec<RoundRecord> {
+        self.inner.rounds.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The latest submissions, oldest first.
+    pub fn submissions(&self) -> Vec<Submission> {
+        self.inner
+            .submissions
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Applies from the next round on, the running one keeps its threads.
+    pub fn set_max_threads(&self, max_threads: u32) {
+        *self.inner.pending_threads.lock().unwrap() = Some(max_threads);
+        if !self.inner.running.load(Ordering::Acquire) {
+            self.apply_pending_threads();
+        }
+    }
+
+    fn apply_pending_threads(&self) {
+        if let Some(max_threads) = self.inner.pending_threads.lock().unwrap().take() {
+            self.inner
+                .threads
+                .lock()
+                .unwrap()
+                .set_max_threads(max_threads);
+        }
+    }
+
+    pub fn thread_limit(&self) -> Option<u32> {
+        *self.inner.thread_limit.lock().unwrap()
+    }
+
+    /// Caps the threads of the next rounds without changing `max_threads`. A
+    /// running round on more threads than that is restarted on the same block.
+    pub fn set_thread_limit(&self, limit: Option<u32>) {
+        *self.inner.thread_limit.lock().unwrap() = limit;
+        let round_threads = self
+            .inner
+            .round
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|r| r.info.threads);
+        if let (Some(limit), Some(threads)) = (limit, round_threads) {
+            if threads > limit {
+                info!("Restarting the round on {} threads", limit);
+                // mined again by the loop, woken up right away
+                *self.inner.latest_block.lock().unwrap() = None;
+                self.cancel_round();
+                self.inner.wake.notify_one();
+            }
+        }
+    }
+
+    /// Takes over the threads, cycles price, deadline diff and polling of
+    /// `config` where they differ from the settings last loaded. Everything
+    /// else needs a restart and is only warned about, so the logged in identity
+    /// is kept. Returns the names of the settings that changed.
+    pub fn reload(&self, config: MinerConfig) -> Vec<&'static str> {
+        let mut loaded = self.inner.loaded.lock().unwrap();
+        let mut changed = vec![];
+        if configured_threads(&config) != configured_threads(&loaded) {
+            self.set_max_threads(configured_threads(&config));
+            changed.push("threads");
+        }
+        {
+            let mut live = self.inner.config.lock().unwrap();
+            live.threads = config.threads;
+            if config.cycles_price != loaded.cycles_price {
+                live.cycles_price = config.cycles_price;
+                changed.push("cycles_price");
+            }
+            if config.deadline_diff != loaded.deadline_diff {
+                live.deadline_diff = config.deadline_diff;
+                changed.push("deadline_diff");
+            }
+            if config.poll != loaded.poll {
+                live.poll = config.poll;
+                changed.push("poll");
+            }
+            if config.schedule != loaded.schedule {
+                live.schedule = config.schedule.clone();
+                changed.push("schedule");
+            }
+            if config.governor != loaded.governor {
+                live.governor = config.governor.clone();
+                changed.push("governor");
+            }
+            if config.placement != loaded.placement {
+                live.placement = config.placement.clone();
+                changed.push("placement");
+            }
+        }
+        if config.wif != loaded.wif
+            || config.dod_canister != loaded.dod_canister
+            || config.siwb_canister != loaded.siwb_canister
+            || config.ic_network != loaded.ic_network
+            || config.ic_url != loaded.ic_url
+        {
+            warn!("Changes to the identity, network or canisters apply after a restart");
+        }
+        *loaded = config;
+        changed
+    }
+
+    pub fn thread_statuses(&self) -> BTreeMap<u32, ThreadStatus> {
+        self.inner.threads.lock().unwrap().get_all_ts()
+    }
+
+    fn fetcher(&self) -> FetcherService {
+        // a snapshot, so no lock is held while talking to the canisters
+        self.inner.fetcher.lock().unwrap().clone()
+    }
+
+    /// Signs in with the configured wif and registers it as a miner.
+    pub async fn register(&self) -> Result<(String, String), String> {
+        let mut fetcher = self.fetcher();
+        let config = self.config();
+        let (btc_address, btc_pubkey) = get_p2tr_from_wif(&config.wif, &config.ic_network);
+
+        let res = match fetcher
+            .connect(config.wif.clone(), btc_address.clone(), btc_pubkey.clone())
+            .await
+        {
+            Ok(_) => {
+                info!("Connected to the miner successfully");
+                match fetcher
+                    .register_miner(btc_address.clone(), btc_pubkey.clone())
+                    .await
+                {
+                    Ok(_) => {
+                        info!("Miner registered successfully");
+                        Ok((btc_address, btc_pubkey))
+                    }
+                    Err(e) => Err(format!("Error registering the miner: {}", e)),
+                }
+            }
+            Err(e) => Err(format!("Error connecting to the miner: {}", e)),
+        };
+
+        *self.inner.fetcher.lock().unwrap() = fetcher;
+        match res {
+            Ok(address) => {
+                *self.inner.address.lock().unwrap() = Some(address.clone());
+                Ok(address)
+            }
+            Err(e) => {
+                self.emit(MinerEvent::Error(e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    /// Mines every new block until `stop` is called.
+    pub fn start(&self) -> JoinHandle<()> {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            while !engine.is_stopped() {
+                engine.sync_clock_if_due().await;
+                if !engine.inner.paused.load(Ordering::Acquire) {
+                    if engine.on_schedule() {
+                        engine.mine_rounds().await;
+                    } else {
+                        engine.keep_alive().await;
+                    }
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(engine.poll_delay()) => {}
+                    _ = engine.inner.wake.notified() => {}
+                }
+            }
+            engine.emit(MinerEvent::Stopped);
+        })
+    }
+
+    /// Cancels the running round and ends the loop started by `start`.
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::Release);
+        self.cancel_round();
+        self.inner.wake.notify_one();
+    }
+
+    /// Cancels the running round and mines nothing until `resume`.
+    pub fn pause(&self) {
+        if !self.inner.paused.swap(true, Ordering::AcqRel) {
+            self.cancel_round();
+            self.emit(MinerEvent::Paused);
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.inner.paused.swap(false, Ordering::AcqRel) {
+            // the block being mined when paused is still worth finishing
+            *self.inner.latest_block.lock().unwrap() = None;
+            self.emit(MinerEvent::Resumed);
+            self.inner.wake.notify_one();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::Acquire)
+    }
+
+    /// Solutions whose submission has not been answered yet.
+    pub fn pending_submissions(&self) -> Vec<PendingSubmission> {
+        self.inner
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Waits up to `timeout` for the submissions in flight, and returns the ones
+    /// still unanswered.
+    pub async fn drain_submissions(&self, timeout: Duration) -> Vec<PendingSubmission> {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                // created before the check, so a removal in between still wakes it
+                let drained = self.inner.drained.notified();
+                if self.inner.pending.lock().unwrap().is_empty() {
+                    return;
+                }
+                drained.await;
+            }
+        })
+        .await;
+        self.pending_submissions()
+    }
+
+    fn add_pending(&self, pending: PendingSubmission) -> u64 {
+        let id = self.inner.next_pending.fetch_add(1, Ordering::Relaxed);
+        self.inner.pending.lock().unwrap().insert(id, pending);
+        id
+    }
+
+    fn remove_pending(&self, id: u64) {
+        self.inner.pending.lock().unwrap().remove(&id);
+        self.inner.drained.notify_waiters();
+    }
+
+    fn cancel_round(&self) {
+        if let Some(cancel) = self.inner.cancel.lock().unwrap().as_ref() {
+            cancel.cancel();
+        }
+    }
+
+    /// Whether the schedule allows mining now, logging when that changes.
+    fn on_schedule(&self) -> bool {
+        let allowed = self
+            .inner
+            .config
+            .lock()
+            .unwrap()
+            .schedule
+            .allows(chrono::Local::now().naive_local());
+        if self.inner.off_schedule.swap(!allowed, Ordering::AcqRel) == allowed {
+            if allowed {
+                info!("Mining window open, resuming with the next block");
+            } else {
+                info!("Outside the mining schedule, waiting for the next window");
+            }
+        }
+        allowed
+    }
+
+    /// Keeps asking for the last block while the schedule has mining off, so the
+    /// session stays in use and the polling knows when blocks are due.
+    async fn keep_alive(&self) {
+        let fetcher = self.fetcher();
+        if fetcher.is_miner() {
+            if let Err(e) = self.poll_last_block(&fetcher).await {
+                info!("{:?}", e);
+            }
+        }
+    }
+
+    /// Mines the latest block, and starts over right away whenever the watcher
+    /// sees a newer block land before the current round is over.
+    async fn mine_rounds(&self) {
+        loop {
+            let block = match self.fetch_block().await {
+                Ok(r) => r,
+                Err(e) => {
+                    info!("{:?}", e);
+                    return;
+                }
+            };
+            self.emit(MinerEvent::BlockFetched {
+                height: block.height,
+                bitwork: block.bitwork.clone(),
+            });
+
+            if !self.mine_block(block).await {
+                return;
+            }
+        }
+    }
+
+    async fn fetch_block(&self) -> Result<FetchedBlock, String> {
+        info!("should fetch blocks?");
+        let fetcher = self.fetcher();
+        if !fetcher.is_miner() {
+            return Err("Not a miner".to_string());
+        }
+
+        let (num, block) = self
+            .poll_last_block(&fetcher)
+            .await?
+            .ok_or_else(|| "No blocks found".to_string())?;
+
+        let mut latest_block = self.inner.latest_block.lock().unwrap();
+        if latest_block.is_some_and(|l| num <= l) {
+            return Err("No new blocks found".to_string());
+        }
+        *latest_block = Some(num);
+
+        let dead_line = round_dead_line(block.next_block_time, self.config().deadline_diff);
+        Ok(FetchedBlock {
+            height: num,
+            hash: block.hash.clone(),
+            bitwork: block.difficulty.clone(),
+            next_block_time: block.next_block_time,
+            dead_line,
+        })
+    }
+
+    /// Returns true if the round was cut short by a newer block.
+    async fn mine_block(&self, block: FetchedBlock) -> bool {
+        let FetchedBlock {
+            height,
+            hash,
+            bitwork,
+            next_block_time,
+            dead_line,
+        } = block;
+        let raw_pubkey = match self.inner.address.lock().unwrap().as_ref() {
+            Some((_, pubkey)) => hex::decode(pubkey).unwrap(),
+            None => {
+                self.emit(MinerEvent::Error("Miner is not registered".to_string()));
+                return false;
+            }
+        };
+        if dead_line == 0 {
+            // no next_block_time, or less of it left than deadline_diff
+            warn!("Block {} is already past its deadline, skipping it", height);
+            self.inner.metrics.round_expired();
+            self.record_round(RoundRecord {
+                height,
+                hashes: 0,
+                outcome: RoundOutcome::Expired,
+                cycles_price: self.config().cycles_price,
+                ended_at: unix_secs(),
+                submission: None,
+            });
+            return false;
+        }
+        self.inner.running.store(true, Ordering::Release);
+        // the round boundary, where thread changes take effect
+        self.apply_pending_threads();
+        let max_threads = self.inner.threads.lock().unwrap().max_threads;
+        let config = self.config();
+        let mut threads = match config
+            .schedule
+            .active(chrono::Local::now().naive_local())
+            .and_then(|w| w.threads)
+        {
+            Some(t) => t.min(max_threads),
+            None => max_threads,
+        };
+        if let Some(limit) = self.thread_limit() {
+            threads = threads.min(limit).max(1);
+        }
+
+        let handle = self.inner.backend.start(MiningJob {
+            bitwork: bitwork.clone(),
+            remote_hash: hash.clone(),
+            raw_pubkey,
+            // the threads check it against the local clock
+            dead_line: self.inner.clock.deadline_to_local(dead_line),
+            threads: Some(threads),
+            height: Some(height),
+            workers: Some(self.inner.threads.clone()),
+            progress_bars: config.progress_bars,
+            placement: config.placement.clone(),
+        });
+        *self.inner.cancel.lock().unwrap() = Some(handle.cancel_token());
+        *self.inner.round.lock().unwrap() = Some(ActiveRound {
+            info: RoundInfo {
+                height,
+                bitwork: bitwork.clone(),
+                remote_hash: hex::encode(&hash),
+                next_block_time,
+                dead_line,
+                threads,
+                started_at: unix_secs(),
+            },
+            hashes: handle.counter(),
+            started: Instant::now(),
+        });
+        let watcher = tokio::spawn(self.clone().watch_new_block(handle.cancel_token()));
+        self.inner.metrics.round_started();
+        self.emit(MinerEvent::RoundStarted { height, threads });
+
+        let cancel = handle.cancel_token();
+        let counter = handle.counter();
+        let outcome = match handle.result().await {
+            Ok(result) => {
+                self.inner.metrics.solution_found();
+                self.emit(MinerEvent::SolutionFound {
+                    height,
+                    result: result.clone(),
+                });
+                let id = self.add_pending(PendingSubmission {
+                    height,
+                    remote_hash: hex::encode(&hash),
+                    result: result.clone(),
+                    found_at: unix_secs(),
+                });
+                let engine = self.clone();
+                let bitwork = bitwork.clone();
+                tokio::spawn(async move {
+                    engine.submit(height, hash, bitwork, result).await;
+                    engine.remove_pending(id);
+                });
+                RoundOutcome::Found
+            }
+            Err(e) => {
+                error!("Mined Error on {}: {}", self.inner.backend.name(), e);
+                if e == "Exited on deadline" {
+                    self.inner.metrics.round_expired();
+                    RoundOutcome::Expired
+                } else if cancel.is_cancelled() {
+                    RoundOutcome::Cancelled
+                } else {
+                    self.emit(MinerEvent::Error(e.clone()));
+                    RoundOutcome::Failed(e)
+                }
+            }
+        };
+        self.record_round(RoundRecord {
+            height,
+            hashes: counter.load(Ordering::Relaxed),
+            outcome,
+            cycles_price: config.cycles_price,
+            ended_at: unix_secs(),
+            submission: None,
+        });
+        self.emit(MinerEvent::RoundEnded {
+            height,
+            hashes: counter.load(Ordering::Relaxed),
+        });
+        *self.inner.cancel.lock().unwrap() = None;
+        {
+            // under the lock, so a scrape never counts the round twice or not at all
+            let mut round = self.inner.round.lock().unwrap();
+            self.inner
+                .metrics
+                .add_hashes(counter.load(Ordering::Relaxed));
+            *round = None;
+        }
+        self.inner.running.store(false, Ordering::Release);
+        self.apply_pending_threads();
+
+        watcher.abort();
+        matches!(watcher.await, Ok(true)) && !self.is_stopped()
+    }
+
+    /// Polls the canister while a round is running and cancels it once a block
+    /// newer than the one being mined shows up. Returns true if it did.
+    async fn watch_new_block(self, cancel: CancelToken) -> bool {
+        loop {
+            let mut delay = self.poll_delay();
+            if !self.config().schedule.is_empty() {
+                delay = delay.min(SCHEDULE_CHECK_INTERVAL);
+            }
+            tokio::time::sleep(delay).await;
+            if cancel.is_cancelled() {
+                return false;
+            }
+            if !self.on_schedule() {
+                info!("Mining window closed, stopping the round");
+                cancel.cancel();
+                return false;
+            }
+
+            let latest_block = *self.inner.latest_block.lock().unwrap();
+            match self.poll_last_block(&self.fetcher()).await {
+                Ok(Some((num, _))) if latest_block.is_some_and(|l| num > l) => {
+                    info!("New block {} found mid-round, restarting mining", num);
+                    cancel.cancel();
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    info!("{:?}", e);
+                }
+            }
+        }
+    }
+
+    /// `get_last_block`, keeping track of when the next block is due and of
+    /// failed polls in a row for [`poll_delay`](Self::poll_delay).
+    async fn poll_last_block(
+        &self,
+        fetcher: &FetcherService,
+    ) -> Result<Option<(u64, BlockData)>, String> {
+        let res = fetcher.get_last_block().await;
+        match res.as_ref() {
+            Ok(block) => {
+                self.inner.poll_errors.store(0, Ordering::Relaxed);
+                if let Some((_, block)) = block {
+                    *self.inner.next_block_time.lock().unwrap() = Some(block.next_block_time);
+                }
+            }
+            Err(_) => {
+                self.inner.poll_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+
+    /// How long to wait before the next `get_last_block`.
+    fn poll_delay(&self) -> Duration {
+        self.config().poll.next_delay(
+            self.ic_now(),
+            *self.inner.next_block_time.lock().unwrap(),
+            self.inner.poll_errors.load(Ordering::Relaxed),
+        )
+    }
+
+    async fn submit(
+        &self,
+        height: u64,
+        remote_hash: Vec<u8>,
+        bitwork: Bitwork,
+        result: MiningResultType,
+    ) {
+        let (btc_address, btc_pubkey) = match self.inner.address.lock().unwrap().clone() {
+            Some(address) => address,
+            None => return,
+        };
+
+        let config = self.config();
+        let res = self
+            .fetcher()
+            .submit_result(
+                remote_hash,
+                &bitwork,
+                hex::decode(btc_pubkey).unwrap(),
+                btc_address,
+                config.wif,
+                result,
+                config.cycles_price,
+            )
+            .await;
+        self.inner.metrics.submitted(&res);
+        let submission = match res {
+            Ok(r) => {
+                self.emit(MinerEvent::Submitted {
+                    height,
+                    block_height: r.block_height,
+                });
+                Submission {
+                    height,
+                    block_height: Some(r.block_height),
+                    error: None,
+                    at: unix_secs(),
+                }
+            }
+            Err(e) => {
+                self.emit(MinerEvent::Error(format!("Submit failed: {}", e)));
+                Submission {
+                    height,
+                    block_height: None,
+                    error: Some(e.to_string()),
+                    at: unix_secs(),
+                }
+            }
+        };
+        self.record_submission(submission);
+    }
+
+    fn record_round(&self, record: RoundRecord) {
+        let mut rounds = self.inner.rounds.lock().unwrap();
+        if rounds.len() >= ROUND_HISTORY {
+            rounds.pop_front();
+        }
+        rounds.push_back(record);
+    }
+
+    fn record_submission(&self, submission: Submission) {
+        if let Some(round) = self
+            .inner
+            .rounds
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .rev()
+            .find(|r| r.height == submission.height && r.outcome == RoundOutcome::Found)
+        {
+            round.submission = Some(submission.clone());
+        }
+        let mut submissions = self.inner.submissions.lock().unwrap();
+        if submissions.len() >= SUBMISSION_HISTORY {
+            submissions.pop_front();
+        }
+        submissions.push_back(submission);
+    }
+}
+
+/// Unix nanoseconds a round on a block due at `next_block_time` gives up at,
+/// 0 if that is already past.
+fn round_dead_line(next_block_time: u64, deadline_diff: u64) -> u128 {
+    next_block_time.saturating_sub(deadline_diff) as u128
+}
+
+/// Threads `config` mines on: its `threads`, else one per core it pins to as far
+/// as the cgroup quota allows, else every thread available.
+fn configured_threads(config: &MinerConfig) -> u32 {
+    let available = ThreadsManager::get_available_threads();
+    match (config.threads, config.placement.cores.as_ref()) {
+        (Some(threads), _) => threads,
+        (None, Some(cores)) => (cores.len() as u32).clamp(1, available),
+        (None, None) => available,
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+impl MinerEngine {
+    /// An engine on `threads` that is never registered, on [`crate::backend::IdleBackend`].
+    pub(crate) fn idle(threads: Option<u32>) -> Self {
+        let mut config = MinerConfig::new(String::new(), 0);
+        config.threads = threads;
+        MinerEngine::new(config, Arc::new(crate::backend::IdleBackend)).unwrap()
+    }
+
+    /// Records a running round on `threads` the way `mine_block` does, for tests
+    /// that need one without a canister. Nothing ends it, like a backend that
+    /// does not honour being cancelled.
+    pub(crate) fn begin_round(&self, threads: u32) {
+        *self.inner.cancel.lock().unwrap() = Some(CancelToken::new());
+        *self.inner.round.lock().unwrap() = Some(ActiveRound {
+            info: RoundInfo {
+                height: 1,
+                bitwork: Bitwork {
+                    pre: 1,
+                    post_hex: String::new(),
+                },
+                remote_hash: String::new(),
+                next_block_time: 0,
+                dead_line: 0,
+                threads,
+                started_at: unix_secs(),
+            },
+            hashes: Arc::new(AtomicU64::new(0)),
+            started: Instant::now(),
+        });
+        self.inner.running.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::affinity::WorkerPlacement;
+    use crate::backend::IdleBackend;
+    use crate::engine::{
+        round_dead_line, save_pending, MinerEngine, MinerEvent, PendingSubmission,
+    };
+    use crate::threads::ThreadsManager;
+    use crate::types::{MiningResult, MiningResultType};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn engines_are_independent() {
+        let a = MinerEngine::idle(Some(2));
+        let b = MinerEngine::idle(Some(3));
+        a.pause();
+        a.set_max_threads(4);
+
+        assert!(a.status().paused);
+        assert!(!b.status().paused);
+        assert_eq!(a.status().max_threads, 4);
+        assert_eq!(b.status().max_threads, 3);
+        assert_eq!(a.thread_statuses().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_settings() {
+        let engine = MinerEngine::idle(Some(2));
+        engine.set_cycles_price(7);
+
+        let mut config = engine.config();
+        config.cycles_price = 0;
+        config.threads = Some(3);
+        config.deadline_diff = 8_000_000_000;
+        assert_eq!(
+            engine.reload(config.clone()),
+            vec!["threads", "deadline_diff"]
+        );
+        // set over the api and unchanged in the file
+        assert_eq!(engine.config().cycles_price, 7);
+        assert_eq!(engine.config().deadline_diff, 8_000_000_000);
+        assert_eq!(engine.status().max_threads, 3);
+        assert!(engine.reload(config.clone()).is_empty());
+
+        // a running round keeps its threads until it is over
+        engine.inner.running.store(true, Ordering::Release);
+        config.threads = Some(5);
+        assert_eq!(engine.reload(config), vec!["threads"]);
+        assert_eq!(engine.status().max_threads, 3);
+        engine.inner.running.store(false, Ordering::Release);
+        engine.apply_pending_threads();
+        assert_eq!(engine.status().max_threads, 5);
+    }
+
+    #[tokio::test]
+    async fn reload_derives_threads_like_new() {
+        let engine = MinerEngine::idle(Some(3));
+        let mut config = engine.config();
+        config.threads = None;
+        config.placement = WorkerPlacement {
+            cores: Some(vec![0, 1]),
+            ..Default::default()
+        };
+        assert_eq!(engine.reload(config.clone()), vec!["threads", "placement"]);
+
+        // the same file read at startup
+        let started = MinerEngine::new(config, Arc::new(IdleBackend)).unwrap();
+        assert_eq!(engine.status().max_threads, started.status().max_threads);
+        assert_eq!(
+            engine.status().max_threads,
+            ThreadsManager::get_available_threads().min(2)
+        );
+    }
+
+    #[test]
+    fn dead_line_never_wraps() {
+        assert_eq!(
+            round_dead_line(20_000_000_000, 6_000_000_000),
+            14_000_000_000
+        );
+        // unset, or closer than deadline_diff
+        assert_eq!(round_dead_line(0, 6_000_000_000), 0);
+        assert_eq!(round_dead_line(5_000_000_000, 6_000_000_000), 0);
+    }
+
+    #[tokio::test]
+    async fn pause_resume_stop_events() {
+        let engine = MinerEngine::idle(Some(1));
+        let mut events = engine.subscribe();
+
+        engine.pause();
+        engine.pause();
+        engine.resume();
+        let handle = engine.start();
+        engine.stop();
+        handle.await.unwrap();
+
+        assert!(matches!(events.recv().await, Ok(MinerEvent::Paused)));
+        assert!(matches!(events.recv().await, Ok(MinerEvent::Resumed)));
+        // not registered, so the round never starts
+        assert!(matches!(events.recv().await, Ok(MinerEvent::Stopped)));
+        assert!(engine.status().stopped);
+    }
+
+    fn pending(height: u64) -> PendingSubmission {
+        PendingSubmission {
+            height,
+            remote_hash: "00".to_string(),
+            result: MiningResultType::Cpu(MiningResult {
+                num_bytes: 1,
+                time: 2,
+                nonce: 3,
+            }),
+            found_at: 4,
+        }
+    }
+
+    #[tokio::test]
+    async fn drains_pending_submissions() {
+        let engine = MinerEngine::idle(Some(1));
+        let answered = engine.add_pending(pending(1));
+        engine.add_pending(pending(2));
+
+        let _engine = engine.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            _engine.remove_pending(answered);
+        });
+        let left = engine.drain_submissions(Duration::from_millis(200)).await;
+        assert_eq!(left, vec![pending(2)]);
+
+        let path = std::env::temp_dir().join(format!("dod_pending_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_pending(path, &left).unwrap();
+        save_pending(path, &left).unwrap();
+        let saved: Vec<PendingSubmission> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(saved, vec![pending(2), pending(2)]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,18 +1,25 @@
-use crate::types::{BlockData, LoginDetails, MinerInfo, MiningResultType, SignMessageType};
+use crate::clock::decode_leb128;
+use crate::metrics::Metrics;
+use crate::types::{
+    BlockData, LoginDetails, MinerInfo, MiningResultType, SignMessageType, SubmitError,
+};
 use bip322_simple::simple_signature_with_wif_taproot;
 
 use bitcoin::key::TapTweak;
 use bitcoin::secp256k1::{Secp256k1, XOnlyPublicKey};
-use bitcoin::{Address, Network};
+use bitcoin::{Address, Network, Psbt};
 use candid::{Decode, Encode, Principal};
 use dod_cpu::tx::{compose_submit_result, CreateDodTxExt};
+use dod_utils::bitwork::{Bitwork, BitworkTarget};
 use dod_utils::types::{MinerSubmitPayload, MinerSubmitResponse};
 use ic_agent::agent::EnvelopeContent;
 use ic_agent::identity::{BasicIdentity, DelegatedIdentity, Delegation, SignedDelegation};
 use ic_agent::{Agent, Identity, Signature};
 use log::info;
 use ring::signature::Ed25519KeyPair;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct ClonableIdentity {
@@ -56,12 +63,17 @@ impl Identity for ClonableIdentity {
     }
 }
 
+#[derive(Clone)]
 pub struct FetcherService {
     pub delegation_identity: Option<ClonableIdentity>,
     pub siwb_canister: Principal,
     pub dod_canister: Principal,
     pub ic_network: Option<String>,
+    /// Replica url, by default picked from `ic_network`.
+    pub ic_url: Option<String>,
     pub is_miner: bool,
+    /// Every canister call is timed into these.
+    pub metrics: Arc<Metrics>,
 }
 
 impl Default for FetcherService {
@@ -71,7 +83,9 @@ impl Default for FetcherService {
             siwb_canister: Principal::from_text("be2us-64aaa-aaaaa-qaabq-cai").unwrap(),
             dod_canister: Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai").unwrap(),
             ic_network: Some("local".to_string()),
+            ic_url: None,
             is_miner: false,
+            metrics: Arc::new(Metrics::default()),
         }
     }
 }
@@ -88,7 +102,9 @@ impl FetcherService {
             siwb_canister,
             dod_canister,
             ic_network,
+            ic_url: None,
             is_miner: false,
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
@@ -112,6 +128,10 @@ impl FetcherService {
         self.dod_canister = dod_canister;
     }
 
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
     pub fn set_ic_network(&mut self, ic_network: Option<String>) {
         self.ic_network = ic_network;
     }
@@ -128,6 +148,14 @@ impl FetcherService {
         self.ic_network.clone()
     }
 
+    pub fn set_ic_url(&mut self, ic_url: Option<String>) {
+        self.ic_url = ic_url;
+    }
+
+    pub fn get_ic_url(&self) -> Option<String> {
+        self.ic_url.clone()
+    }
+
     pub fn is_miner(&self) -> bool {
         self.is_miner
     }
@@ -145,13 +173,16 @@ impl FetcherService {
             .expect("Could not get public key");
         let _session = ClonableIdentity::new(session);
 
-        let agent = with_agent(_session.clone(), self.get_ic_network()).await;
+        let agent = with_agent(_session.clone(), self.get_ic_network(), self.get_ic_url()).await;
         let canister = self.get_siwb_canister();
 
+        let started = Instant::now();
         let siwb_prepare_login_call_res = agent
             .update(&canister, "siwb_prepare_login")
             .with_arg(Encode!(&btc_address).map_err(|e| format!("Error encoding: {:?}", e))?)
-            .await
+            .await;
+        self.observe_call("siwb_prepare_login", started, &siwb_prepare_login_call_res);
+        let siwb_prepare_login_call_res = siwb_prepare_login_call_res
             .map_err(|e| format!("Error siwb_prepare_login: {:?}", e))?;
 
         let decoded_message =
@@ -160,6 +191,7 @@ impl FetcherService {
 
         let signature = simple_signature_with_wif_taproot(decoded_message.as_str(), wif.as_str());
 
+        let started = Instant::now();
         let siwb_login_call_res = agent
             .update(&canister, "siwb_login")
             .with_arg(
@@ -172,20 +204,25 @@ impl FetcherService {
                 )
                 .map_err(|e| format!("Error encoding: {:?}", e))?,
             )
-            .await
-            .map_err(|e| format!("Error siwb_login: {:?}", e))?;
+            .await;
+        self.observe_call("siwb_login", started, &siwb_login_call_res);
+        let siwb_login_call_res =
+            siwb_login_call_res.map_err(|e| format!("Error siwb_login: {:?}", e))?;
 
         let login_details = Decode!(siwb_login_call_res.as_slice(), Result<LoginDetails, String>)
             .unwrap_or_else(|e| Err(e.to_string()))?;
 
+        let started = Instant::now();
         let siwb_get_delegation_res = agent
             .query(&canister, "siwb_get_delegation")
             .with_arg(
                 Encode!(&btc_address, &session_key, &login_details.expiration)
                     .map_err(|e| format!("Error encoding: {:?}", e))?,
             )
-            .await
-            .map_err(|e| format!("Error siwb_get_delegation: {:?}", e))?;
+            .await;
+        self.observe_call("siwb_get_delegation", started, &siwb_get_delegation_res);
+        let siwb_get_delegation_res =
+            siwb_get_delegation_res.map_err(|e| format!("Error siwb_get_delegation: {:?}", e))?;
 
         let delegation_result =
             Decode!(siwb_get_delegation_res.as_slice(), Result<crate::types::SignedDelegation, String>)
@@ -219,17 +256,41 @@ impl FetcherService {
         Ok(())
     }
 
+    /// The time certified by the subnet of the dod canister, in unix nanoseconds.
+    pub async fn get_ic_time(&self) -> Result<u64, String> {
+        let agent = with_agent(
+            self.delegation_identity.clone().unwrap(),
+            self.get_ic_network(),
+            self.get_ic_url(),
+        )
+        .await;
+        let started = Instant::now();
+        let cert = agent
+            .read_state_raw(vec![vec!["time".into()]], self.get_dod_canister())
+            .await;
+        self.observe_call("read_state_time", started, &cert);
+        let cert = cert.map_err(|e| format!("Error read_state time: {:?}", e))?;
+
+        let time = ic_agent::lookup_value(&cert, ["time".as_bytes()])
+            .map_err(|e| format!("Error reading time: {:?}", e))?;
+        decode_leb128(time).ok_or_else(|| "Error decoding time".to_string())
+    }
+
     pub async fn get_last_block(&self) -> Result<Option<(u64, BlockData)>, String> {
         let agent = with_agent(
             self.delegation_identity.clone().unwrap(),
             self.get_ic_network(),
+            self.get_ic_url(),
         )
         .await;
+        let started = Instant::now();
         let get_last_block = agent
             .query(&self.get_dod_canister(), "get_last_block")
             .with_arg(Encode!().unwrap())
-            .await
-            .map_err(|e| format!("Error get_last_block: {:?}", e))?;
+            .await;
+        self.observe_call("get_last_block", started, &get_last_block);
+        let get_last_block =
+            get_last_block.map_err(|e| format!("Error get_last_block: {:?}", e))?;
 
         let rrr = Decode!(get_last_block.as_slice(), Option<(u64, BlockData)>)
             .map_err(|e| format!("Error decoding: {:?}", e))?;
@@ -245,13 +306,16 @@ impl FetcherService {
         let agent = with_agent(
             self.delegation_identity.clone().unwrap(),
             self.get_ic_network(),
+            self.get_ic_url(),
         )
         .await;
+        let started = Instant::now();
         let register = agent
             .update(&self.get_dod_canister(), "register")
             .with_arg(Encode!(&btc_address, &public_key).unwrap())
-            .await
-            .map_err(|e| format!("Error who_am_i: {:?}", e))?;
+            .await;
+        self.observe_call("register", started, &register);
+        let register = register.map_err(|e| format!("Error who_am_i: {:?}", e))?;
 
         let rrr = Decode!(register.as_slice(), Result<MinerInfo,String>)
             .map_err(|e| format!("Error decoding: {:?}", e))?;
@@ -266,23 +330,24 @@ impl FetcherService {
         Ok(is_miner)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn submit_result(
         &self,
         remote_hash: Vec<u8>,
+        bitwork: &Bitwork,
         raw_pubkey: Vec<u8>,
         address: String,
         wif: String,
         mining_result: MiningResultType,
         cycles_price: u128,
-    ) -> Result<MinerSubmitResponse, String> {
+    ) -> Result<MinerSubmitResponse, SubmitError> {
         let private_key = bitcoin::key::PrivateKey::from_wif(wif.as_str()).unwrap();
 
         let cycles_price = cycles_price;
-        let (time, nonce, num_bytes) = match mining_result {
-            MiningResultType::Cpu(r) => (r.time, r.nonce, r.num_bytes.to_le_bytes().to_vec()),
-        };
+        let r = mining_result.mining_result();
+        let (time, nonce, num_bytes) = (r.time, r.nonce, r.num_bytes.to_le_bytes().to_vec());
 
-        println!("remote_hash {:?}", hex::encode(remote_hash.clone()));
+        info!("remote_hash {:?}", hex::encode(remote_hash.clone()));
         let composed = compose_submit_result(
             CreateDodTxExt {
                 remote_hash: remote_hash.clone(),
@@ -294,6 +359,9 @@ impl FetcherService {
             },
             &private_key,
         );
+        // a commit tx other than the one mined costs cycles and is rejected anyway
+        verify_commit_bitwork(&composed.signed_commit_psbt, &remote_hash, bitwork)
+            .map_err(SubmitError::Invalid)?;
 
         let payload = MinerSubmitPayload {
             btc_address: address.clone(),
@@ -305,19 +373,58 @@ impl FetcherService {
         let agent = with_agent(
             self.delegation_identity.clone().unwrap(),
             self.get_ic_network(),
+            self.get_ic_url(),
         )
         .await;
+        let started = Instant::now();
         let submitted = agent
             .update(&self.get_dod_canister(), "miner_submit_hash")
             .with_arg(Encode!(&payload).unwrap())
-            .await
-            .map_err(|e| format!("Error miner_submit_hash: {:?}", e))?;
+            .await;
+        self.observe_call("miner_submit_hash", started, &submitted);
+        let submitted = submitted
+            .map_err(|e| SubmitError::Call(format!("Error miner_submit_hash: {:?}", e)))?;
 
-        let submitted_result = Decode!(submitted.as_slice(), Result<MinerSubmitResponse, String>)
-            .map_err(|e| format!("Error decoding: {:?}", e))?;
+        let submitted_result =
+            Decode!(submitted.as_slice(), Result<MinerSubmitResponse, String>)
+                .map_err(|e| SubmitError::Call(format!("Error decoding: {:?}", e)))?;
 
         info!("Result Submitted: {:?}", submitted_result);
-        submitted_result
+        submitted_result.map_err(SubmitError::Rejected)
+    }
+
+    fn observe_call<T, E>(&self, method: &str, started: Instant, res: &Result<T, E>) {
+        self.metrics
+            .observe_call(method, started.elapsed(), res.is_ok());
+    }
+}
+
+/// Checks that the commit tx in `signed_commit_psbt` has a txid meeting
+/// `bitwork` on `remote_hash`, as the one built while mining did.
+pub fn verify_commit_bitwork(
+    signed_commit_psbt: &str,
+    remote_hash: &[u8],
+    bitwork: &Bitwork,
+) -> Result<(), String> {
+    let psbt = Psbt::from_str(signed_commit_psbt)
+        .map_err(|e| format!("Invalid solution, commit psbt does not parse: {}", e))?;
+    let txid = psbt.unsigned_tx.compute_txid().to_string();
+    let target = BitworkTarget::new(remote_hash, bitwork).map_err(|_| {
+        format!(
+            "Invalid solution, bitwork {}.{} is malformed",
+            bitwork.pre, bitwork.post_hex
+        )
+    })?;
+    if target
+        .matches_txid(&txid)
+        .map_err(|e| format!("Invalid solution, commit txid {}: {}", txid, e))?
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid solution, commit txid {} does not meet bitwork {}.{}",
+            txid, bitwork.pre, bitwork.post_hex
+        ))
     }
 }
 
@@ -330,8 +437,12 @@ pub fn create_basic_identity() -> Result<impl Identity + 'static, String> {
     ))
 }
 
-pub async fn with_agent(identity: ClonableIdentity, ic_network: Option<String>) -> Agent {
-    let agent = create_agent(identity, ic_network.clone())
+pub async fn with_agent(
+    identity: ClonableIdentity,
+    ic_network: Option<String>,
+    ic_url: Option<String>,
+) -> Agent {
+    let agent = create_agent(identity, ic_network.clone(), ic_url)
         .await
         .expect("Could not create an agent.");
 
@@ -347,8 +458,15 @@ pub async fn with_agent(identity: ClonableIdentity, ic_network: Option<String>)
 pub async fn create_agent(
     identity: ClonableIdentity,
     ic_network: Option<String>,
+    ic_url: Option<String>,
 ) -> Result<Agent, String> {
-    if ic_network.is_none() || ic_network.unwrap() == "local" {
+    if let Some(url) = ic_url {
+        Agent::builder()
+            .with_url(url)
+            .with_identity(identity)
+            .build()
+            .map_err(|e| format!("{:?}", e))
+    } else if ic_network.is_none() || ic_network.unwrap() == "local" {
         let port_env = std::env::var("IC_REF_PORT").unwrap_or_else(|_| "8080".into());
         let port = port_env
             .parse::<u32>()
@@ -391,3 +509,79 @@ pub fn get_p2tr_from_wif(wif: &str, network: &str) -> (String, String) {
         key_pair.public_key().to_string(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use crate::affinity::WorkerPlacement;
+    use crate::fetcher::{get_p2tr_from_wif, verify_commit_bitwork};
+    use crate::miner::multi_run_v3;
+    use crate::telemetry::RoundMonitor;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::{Network, PrivateKey};
+    use dod_cpu::tx::{compose_submit_result, CreateDodTxExt};
+    use dod_utils::bitwork::Bitwork;
+    use dod_utils::mine::CancelToken;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn verifies_commit_bitwork() {
+        let key = PrivateKey::new(SecretKey::from_slice(&[7u8; 32]).unwrap(), Network::Bitcoin);
+        let (address, pubkey) = get_p2tr_from_wif(&key.to_wif(), "ic");
+        let raw_pubkey = hex::decode(pubkey).unwrap();
+        let remote_hash =
+            hex::decode("98799b250c911fe0df86cd59066e329d93bfb3d35fa57cdd3b243e2a8eec1b45")
+                .unwrap();
+        let bitwork = Bitwork {
+            pre: 2,
+            post_hex: "0".to_string(),
+        };
+
+        let dead_line = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            + 30_000_000_000u128;
+        let result = multi_run_v3(
+            bitwork.clone(),
+            remote_hash.clone(),
+            raw_pubkey.clone(),
+            Some(1),
+            dead_line,
+            CancelToken::new(),
+            RoundMonitor::default(),
+            WorkerPlacement::default(),
+        )
+        .await
+        .unwrap();
+
+        let composed = compose_submit_result(
+            CreateDodTxExt {
+                remote_hash: remote_hash.clone(),
+                raw_pubkey,
+                time: result.time,
+                nonce: result.nonce,
+                num_bytes: result.num_bytes.to_le_bytes().to_vec(),
+                address,
+            },
+            &key,
+        );
+        assert_eq!(
+            verify_commit_bitwork(&composed.signed_commit_psbt, &remote_hash, &bitwork),
+            Ok(())
+        );
+
+        // the solution of another block
+        let mut other = remote_hash.clone();
+        other[0] ^= 0xf0;
+        let err = verify_commit_bitwork(&composed.signed_commit_psbt, &other, &bitwork);
+        assert!(err.unwrap_err().starts_with("Invalid solution"));
+
+        // a malformed bitwork fails the solution too, not the submission
+        let malformed = Bitwork {
+            pre: 2,
+            post_hex: "g".to_string(),
+        };
+        let err = verify_commit_bitwork(&composed.signed_commit_psbt, &remote_hash, &malformed);
+        assert!(err.unwrap_err().starts_with("Invalid solution"));
+    }
+}
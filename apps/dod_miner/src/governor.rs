@@ -0,0 +1,323 @@
+use crate::engine::MinerEngine;
+use dod_cpu::threads::get_available_threads;
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use sysinfo::System;
+
+pub const DEFAULT_MAX_TEMP: f64 = 85.0;
+/// How far under `max_temp` threads are given back by default.
+pub const DEFAULT_TEMP_HYSTERESIS: f64 = 10.0;
+pub const DEFAULT_GOVERNOR_INTERVAL: f64 = 10.0;
+
+const THERMAL_DIR: &str = "/sys/class/thermal";
+const HWMON_DIR: &str = "/sys/class/hwmon";
+
+/// What the governor looks at, `None` where a sensor is missing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SensorReading {
+    /// Hottest sensor, in degrees Celsius.
+    pub temperature: Option<f64>,
+    /// One minute load average per core, the miner's own threads included.
+    pub load: Option<f64>,
+}
+
+pub trait Sensors: Send + Sync {
+    fn read(&self) -> SensorReading;
+}
+
+/// The sensors of this machine: thermal zones, or hwmon where there are none,
+/// and the load average.
+#[derive(Debug, Default)]
+pub struct SystemSensors;
+
+/// Hottest of the millidegree files `name` matches in the directories under `dir`.
+fn max_millidegrees(dir: &str, name: impl Fn(&str) -> bool) -> Option<f64> {
+    let mut max: Option<f64> = None;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let files = match fs::read_dir(entry.path()) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for file in files.flatten() {
+            if !file.file_name().to_str().is_some_and(&name) {
+                continue;
+            }
+            if let Some(t) = read_millidegrees(&file.path()) {
+                max = Some(max.map_or(t, |m| m.max(t)));
+            }
+        }
+    }
+    max
+}
+
+fn read_millidegrees(path: &Path) -> Option<f64> {
+    let value = fs::read_to_string(path).ok()?.trim().parse::<i64>().ok()?;
+    Some(value as f64 / 1000.0)
+}
+
+impl Sensors for SystemSensors {
+    fn read(&self) -> SensorReading {
+        let temperature = max_millidegrees(THERMAL_DIR, |f| f == "temp").or_else(|| {
+            max_millidegrees(HWMON_DIR, |f| {
+                f.starts_with("temp") && f.ends_with("_input")
+            })
+        });
+        let load = System::load_average().one / get_available_threads() as f64;
+        SensorReading {
+            temperature,
+            load: Some(load).filter(|l| *l > 0.0),
+        }
+    }
+}
+
+/// Limits on the threads the miner runs while the machine is hot or busy.
+///
+/// ```toml
+/// [profiles.mainnet.governor]
+/// max_temp = 80
+/// min_threads = 2
+/// ```
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GovernorConfig {
+    /// Degrees Celsius at which a thread is taken away.
+    #[serde(default = "default_max_temp")]
+    pub max_temp: f64,
+    /// Degrees Celsius under which a thread is given back, `max_temp` less
+    /// [`DEFAULT_TEMP_HYSTERESIS`] if left out.
+    pub resume_temp: Option<f64>,
+    /// Load per core at which a thread is taken away, ignored if left out.
+    pub max_load: Option<f64>,
+    #[serde(default = "default_min_threads")]
+    pub min_threads: u32,
+    /// Never more than the miner's thread count.
+    pub max_threads: Option<u32>,
+    /// Seconds between readings.
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+}
+
+fn default_max_temp() -> f64 {
+    DEFAULT_MAX_TEMP
+}
+
+fn default_min_threads() -> u32 {
+    1
+}
+
+fn default_interval() -> f64 {
+    DEFAULT_GOVERNOR_INTERVAL
+}
+
+impl Default for GovernorConfig {
+    fn default() -> Self {
+        GovernorConfig {
+            max_temp: DEFAULT_MAX_TEMP,
+            resume_temp: None,
+            max_load: None,
+            min_threads: default_min_threads(),
+            max_threads: None,
+            interval: DEFAULT_GOVERNOR_INTERVAL,
+        }
+    }
+}
+
+impl GovernorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.interval.is_finite() || self.interval <= 0.0 {
+            return Err("governor interval must be a positive number of seconds".to_string());
+        }
+        if self.min_threads == 0 || self.max_threads.is_some_and(|m| m < self.min_threads) {
+            return Err(
+                "governor threads must be at least 1 and min_threads at most max_threads"
+                    .to_string(),
+            );
+        }
+        if self.resume_temp.is_some_and(|t| t >= self.max_temp) {
+            return Err("governor resume_temp must be below max_temp".to_string());
+        }
+        if self.max_load.is_some_and(|l| !l.is_finite() || l <= 0.0) {
+            return Err("governor max_load must be a positive number".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(self.interval)
+    }
+
+    fn resume_temp(&self) -> f64 {
+        self.resume_temp
+            .unwrap_or(self.max_temp - DEFAULT_TEMP_HYSTERESIS)
+    }
+
+    /// The thread limit after `reading`, one less than `current` while too hot
+    /// or busy and one more once cool again, within the configured limits and
+    /// at most `threads`, the miner's thread count.
+    pub fn next_limit(&self, current: u32, threads: u32, reading: &SensorReading) -> u32 {
+        let upper = self.max_threads.map_or(threads, |m| m.min(threads)).max(1);
+        let lower = self.min_threads.min(upper);
+        let busy = |l: f64| self.max_load.is_some_and(|max| l >= max);
+        let hot = reading.temperature.is_some_and(|t| t >= self.max_temp)
+            || reading.load.is_some_and(busy);
+        let cool = reading.temperature.is_none_or(|t| t < self.resume_temp())
+            && !reading.load.is_some_and(busy);
+
+        let limit = if hot {
+            current.saturating_sub(1)
+        } else if cool {
+            current.saturating_add(1)
+        } else {
+            current
+        };
+        limit.clamp(lower, upper)
+    }
+}
+
+/// Adjusts the thread limit of `engine` from `sensors` every interval of its
+/// governor config, until the process exits. Does nothing while the config has
+/// no governor.
+pub async fn run(engine: MinerEngine, sensors: Arc<dyn Sensors>) {
+    loop {
+        let config = engine.config();
+        let governor = match config.governor {
+            Some(governor) => governor,
+            None => {
+                engine.set_thread_limit(None);
+                tokio::time::sleep(Duration::from_secs_f64(DEFAULT_GOVERNOR_INTERVAL)).await;
+                continue;
+            }
+        };
+
+        adjust(&engine, &governor, sensors.as_ref());
+        tokio::time::sleep(governor.interval()).await;
+    }
+}
+
+/// Takes one reading of `sensors` and sets the thread limit of `engine` from
+/// it. Returns the new limit, the current one while the running round has not
+/// come down to it yet.
+pub fn adjust(engine: &MinerEngine, governor: &GovernorConfig, sensors: &dyn Sensors) -> u32 {
+    let status = engine.status();
+    let threads = status.max_threads;
+    let current = engine.thread_limit().unwrap_or(threads);
+    // a lower limit only takes effect once the backend has stopped the round,
+    // stepping on before that would cut the threads down to min_threads
+    // without the machine ever running on fewer
+    if status.round.is_some_and(|r| r.threads > current) {
+        return current;
+    }
+    let reading = sensors.read();
+    let limit = governor.next_limit(current, threads, &reading);
+    if limit != current {
+        info!(
+            "Governor: {} threads at {} C and load {}",
+            limit,
+            reading
+                .temperature
+                .map_or("-".to_string(), |t| format!("{:.1}", t)),
+            reading
+                .load
+                .map_or("-".to_string(), |l| format!("{:.2}", l))
+        );
+    }
+    engine.set_thread_limit(Some(limit));
+    limit
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::MinerEngine;
+    use crate::governor::{adjust, GovernorConfig, SensorReading, Sensors};
+    use std::sync::Mutex;
+
+    struct FakeSensors(Mutex<SensorReading>);
+
+    impl Sensors for FakeSensors {
+        fn read(&self) -> SensorReading {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn reading(temperature: f64, load: f64) -> SensorReading {
+        SensorReading {
+            temperature: Some(temperature),
+            load: Some(load),
+        }
+    }
+
+    #[test]
+    fn throttles_within_limits() {
+        let governor = GovernorConfig {
+            max_temp: 80.0,
+            max_load: Some(1.5),
+            min_threads: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(governor.next_limit(8, 8, &reading(85.0, 1.0)), 7);
+        assert_eq!(governor.next_limit(2, 8, &reading(90.0, 1.0)), 2);
+        assert_eq!(governor.next_limit(6, 8, &reading(60.0, 2.0)), 5);
+        // between resume_temp and max_temp it holds
+        assert_eq!(governor.next_limit(6, 8, &reading(75.0, 1.0)), 6);
+        assert_eq!(governor.next_limit(6, 8, &reading(65.0, 1.0)), 7);
+        assert_eq!(governor.next_limit(8, 8, &reading(65.0, 1.0)), 8);
+        // the miner's threads were lowered
+        assert_eq!(governor.next_limit(8, 4, &reading(75.0, 1.0)), 4);
+        // no sensors, no throttling
+        assert_eq!(governor.next_limit(3, 4, &SensorReading::default()), 4);
+    }
+
+    #[test]
+    fn validates() {
+        assert!(GovernorConfig::default().validate().is_ok());
+        let governor = GovernorConfig {
+            resume_temp: Some(90.0),
+            ..Default::default()
+        };
+        assert!(governor.validate().is_err());
+        let governor = GovernorConfig {
+            min_threads: 4,
+            max_threads: Some(2),
+            ..Default::default()
+        };
+        assert!(governor.validate().is_err());
+    }
+
+    #[test]
+    fn limits_engine_threads() {
+        let engine = MinerEngine::idle(Some(4));
+        let governor = GovernorConfig::default();
+        let sensors = FakeSensors(Mutex::new(reading(95.0, 1.0)));
+
+        assert_eq!(adjust(&engine, &governor, &sensors), 3);
+        assert_eq!(adjust(&engine, &governor, &sensors), 2);
+        assert_eq!(engine.status().thread_limit, Some(2));
+
+        *sensors.0.lock().unwrap() = reading(50.0, 1.0);
+        assert_eq!(adjust(&engine, &governor, &sensors), 3);
+        // the miner's own thread count is left alone
+        assert_eq!(engine.status().max_threads, 4);
+    }
+
+    #[test]
+    fn waits_for_round_to_restart() {
+        let engine = MinerEngine::idle(Some(4));
+        let governor = GovernorConfig::default();
+        let sensors = FakeSensors(Mutex::new(reading(95.0, 1.0)));
+
+        // the round keeps its 4 threads however often it is cancelled
+        engine.begin_round(4);
+        assert_eq!(adjust(&engine, &governor, &sensors), 3);
+        assert_eq!(adjust(&engine, &governor, &sensors), 3);
+        assert_eq!(engine.status().thread_limit, Some(3));
+
+        // restarted on the lower limit, the governor steps on
+        engine.begin_round(3);
+        assert_eq!(adjust(&engine, &governor, &sensors), 2);
+    }
+}
@@ -1,5 +1,18 @@
+pub mod affinity;
+pub mod api;
+pub mod backend;
+pub mod bench;
+pub mod clock;
+pub mod config;
+pub mod engine;
 pub mod fetcher;
+pub mod governor;
+pub mod metrics;
 pub mod miner;
-pub mod state;
+pub mod polling;
+pub mod schedule;
+pub mod scheduler;
+pub mod telemetry;
 pub mod threads;
+pub mod tui;
 pub mod types;
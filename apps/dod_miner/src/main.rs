@@ -1,258 +1,333 @@
-use candid::Principal;
 use clap::Parser;
-use dod_miner::fetcher::get_p2tr_from_wif;
-
-use dod_miner::miner::multi_run_v3;
-use dod_miner::state::{LATEST_BLOCK, MINER, RUNNING, THREADS};
-use dod_miner::types::{MiningResultExt, MiningResultType};
-use dod_utils::bitwork::Bitwork;
+use dod_miner::api::DEFAULT_API_ADDR;
+use dod_miner::bench::{recommend_threads, run_bench, save_threads, THREADS_ENV};
+use dod_miner::config::{ProfileConfig, SettingsSource, DEFAULT_CONFIG_FILE};
+use dod_miner::engine::{
+    save_pending, MinerEngine, MinerEvent, SUBMIT_DRAIN_TIMEOUT, UNSUBMITTED_FILE,
+};
+use dod_miner::governor::SystemSensors;
+use dod_miner::telemetry::format_hashrate;
+use dod_miner::tui::{self, LogTail, LOG_TAIL_LINES};
 use dotenv::dotenv;
-use flume::Sender;
 use log::{error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
+use tokio::sync::broadcast;
+
+/// Exit codes, 0 being a clean shutdown with every solution submitted.
+const EXIT_REGISTER_FAILED: i32 = 1;
+/// Some solutions were not answered for in time and are saved to [`UNSUBMITTED_FILE`].
+const EXIT_UNSUBMITTED: i32 = 2;
+/// Some solutions were not answered for in time and could not be saved either.
+const EXIT_UNSUBMITTED_LOST: i32 = 3;
+/// The config file or the command line is invalid.
+const EXIT_BAD_CONFIG: i32 = 4;
+/// A second signal arrived before the shutdown was over.
+const EXIT_FORCED: i32 = 130;
 
 #[derive(Parser)] // requires `derive` feature
 enum DodCli {
-    Miner(MinerArgs),
+    Miner(Box<MinerArgs>),
+    Bench(BenchArgs),
 }
 
 #[derive(clap::Args)]
 struct MinerArgs {
+    /// Config file with the profiles, read if present
+    #[arg(long = "config")]
+    config: Option<String>,
+    /// Profile of the config file, its `default_profile` if not given
+    #[arg(long = "profile")]
+    profile: Option<String>,
     #[arg(long = "threads")]
     threads: Option<u32>,
     #[arg(long = "wif")]
     wif: String,
+    /// In trillions of cycles
     #[arg(long = "cycles_price")]
-    cycles_price: String, // #[arg(long = "siwb_canister")]
-                          // siwb_canister: Option<String>,
-                          // #[arg(long = "dod_canister")]
-                          // dod_canister: Option<String>,
+    cycles_price: Option<f64>,
+    /// `ic` or `local`
+    #[arg(long = "ic_network")]
+    ic_network: Option<String>,
+    /// Replica url, picked from the network if not given
+    #[arg(long = "ic_url")]
+    ic_url: Option<String>,
+    #[arg(long = "dod_canister")]
+    dod_canister: Option<String>,
+    #[arg(long = "siwb_canister")]
+    siwb_canister: Option<String>,
+    /// log4rs config file, config/log4rs.yaml if not given
+    #[arg(long = "log_config")]
+    log_config: Option<String>,
+    /// Serve the control and status api, on 127.0.0.1:7878 unless an address is given. It has
+    /// no authentication, so keep it on a loopback address
+    #[arg(long = "api", num_args = 0..=1, default_missing_value = DEFAULT_API_ADDR)]
+    api: Option<SocketAddr>,
+    /// Show a live dashboard instead of the log, which then goes to log/dod_miner.log
+    #[arg(long = "tui")]
+    tui: bool,
+    /// Seconds between polls for a new block while its timing is unknown or it is overdue [default: 5]
+    #[arg(long = "poll_interval", value_parser = parse_secs)]
+    poll_interval: Option<f64>,
+    /// Seconds between polls around the time the next block is expected [default: 0.5]
+    #[arg(long = "near_poll_interval", value_parser = parse_secs)]
+    near_poll_interval: Option<f64>,
+    /// Seconds before the next block is due that a round gives up [default: 5]
+    #[arg(long = "deadline_diff", value_parser = parse_secs)]
+    deadline_diff: Option<f64>,
+    /// Pin the mining threads to these cpus, e.g. 0-3,8, one thread per cpu unless --threads is given
+    #[arg(long = "cores")]
+    cores: Option<String>,
+    /// Pin the mining threads to the cpus of this NUMA node
+    #[arg(long = "numa_node")]
+    numa_node: Option<u32>,
+    /// Run the mining threads at SCHED_IDLE, so they only get cpu time nothing else wants
+    #[arg(long = "sched_idle")]
+    sched_idle: bool,
+    /// Nice level of the mining threads, -20 to 19
+    #[arg(long = "nice", allow_hyphen_values = true)]
+    nice: Option<i32>,
+}
+
+impl MinerArgs {
+    /// The settings given on the command line, to put over the profile.
+    fn overrides(&self) -> ProfileConfig {
+        ProfileConfig {
+            ic_network: self.ic_network.clone(),
+            ic_url: self.ic_url.clone(),
+            dod_canister: self.dod_canister.clone(),
+            siwb_canister: self.siwb_canister.clone(),
+            deadline_diff: self.deadline_diff,
+            poll_interval: self.poll_interval,
+            near_poll_interval: self.near_poll_interval,
+            threads: self.threads,
+            cycles_price: self.cycles_price,
+            log_config: self.log_config.clone(),
+            schedule: None,
+            governor: None,
+            cores: self.cores.clone(),
+            numa_node: self.numa_node,
+            sched_idle: Some(true).filter(|_| self.sched_idle),
+            nice: self.nice,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// Highest thread count to try, defaults to every thread available
+    #[arg(long = "max_threads")]
+    max_threads: Option<u32>,
+    /// Seconds to mine at each thread count
+    #[arg(long = "seconds", default_value_t = 5)]
+    seconds: u64,
+    /// Write the recommended thread count to `.env` for the miner to pick up
+    #[arg(long = "save")]
+    save: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    let DodCli::Miner(minter_args) = DodCli::parse();
+    let minter_args = match DodCli::parse() {
+        DodCli::Miner(args) => args,
+        DodCli::Bench(args) => return bench(args).await,
+    };
     dotenv().ok();
-    log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
-
-    let _siwb_canister = String::from("mwm4a-eiaaa-aaaah-aebnq-cai");
-    let _dod_canister = String::from("tmhkz-dyaaa-aaaah-aedeq-cai");
-    let _ic_network = String::from("ic");
-    let _account_wif = minter_args.wif;
 
-    let _deadline_diff = String::from("5000000000");
-    let _cycles_price = (minter_args.cycles_price.parse::<f64>().unwrap()
-        * u128::pow(10, 12) as f64)
-        .round() as u128;
-
-    let _threads = minter_args
-        .threads
-        .map_or_else(|| None, |f| Some(f.clone()));
+    // the profile, then the thread count saved by `bench`, then the command line
+    let source = SettingsSource {
+        path: minter_args
+            .config
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string()),
+        profile: minter_args.profile.clone(),
+        required: minter_args.config.is_some(),
+        overrides: ProfileConfig {
+            threads: std::env::var(THREADS_ENV)
+                .ok()
+                .and_then(|t| t.parse::<u32>().ok()),
+            ..Default::default()
+        }
+        .merge(minter_args.overrides()),
+    };
+    let settings = match source.load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_BAD_CONFIG);
+        }
+    };
+    let mut config = match settings.miner_config(minter_args.wif.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_BAD_CONFIG);
+        }
+    };
+    config.progress_bars = !minter_args.tui;
 
-    if _threads.is_some() {
-        tokio::spawn(async move {
-            let mut tm = THREADS.lock().await;
-            tm.set_max_threads(_threads.unwrap());
-        });
+    let tail = LogTail::new(LOG_TAIL_LINES);
+    if minter_args.tui {
+        tui::init_logging(&tail).unwrap();
+    } else {
+        log4rs::init_file(settings.log_config(), Default::default()).unwrap();
     }
 
-    let (tx, rx) = flume::unbounded::<MiningResultExt>();
-
-    let _registered = register(
-        _dod_canister.as_str(),
-        _siwb_canister.as_str(),
-        _ic_network.as_str(),
-        _account_wif.as_str(),
-    )
-    .await;
+    let engine = match MinerEngine::with_cpu(config) {
+        Ok(engine) => engine,
+        Err(e) => {
+            error!("Invalid config: {}", e);
+            std::process::exit(EXIT_BAD_CONFIG);
+        }
+    };
+    tokio::spawn(log_events(engine.subscribe()));
+    if minter_args.tui {
+        tokio::spawn(tui::run(engine.clone(), tail));
+    }
+    if let Some(addr) = minter_args.api {
+        match dod_miner::api::serve(engine.clone(), addr) {
+            Ok(server) => {
+                tokio::spawn(server);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(EXIT_BAD_CONFIG);
+            }
+        }
+    }
+    tokio::spawn(dod_miner::governor::run(
+        engine.clone(),
+        Arc::new(SystemSensors),
+    ));
+    tokio::spawn(dod_miner::config::watch(
+        engine.clone(),
+        source,
+        minter_args.wif.clone(),
+    ));
 
-    let (btc_address, btc_pubkey) = _registered.unwrap();
-    _schedule_fetch(
-        (btc_address.clone(), btc_pubkey.clone()),
-        _threads,
-        tx.clone(),
-        Some(u64::from_str_radix(_deadline_diff.as_str(), 10).unwrap_or(5_000_000_000)),
-    )
-    .await
-    .unwrap();
+    let _engine = engine.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down, stopping mining threads");
+        _engine.stop();
+        shutdown_signal().await;
+        error!("Forced to exit before the shutdown was over");
+        std::process::exit(EXIT_FORCED);
+    });
 
-    //
-    // _schedule_fetch(tx.clone(), None).await.unwrap();
-    //
-    // // println!("123123");
-    //
-    // // first run
-    // // runner(tx.clone(), None, None);
-    //
-    let result = rx.iter();
-    for r in result {
-        submit(
-            (btc_address.clone(), btc_pubkey.clone()),
-            _account_wif.clone(),
-            r,
-            _cycles_price.clone(),
-        );
+    if engine.register().await.is_err() {
+        std::process::exit(EXIT_REGISTER_FAILED);
     }
+    let _ = engine.start().await;
+    std::process::exit(shutdown(&engine).await);
 }
 
-async fn _schedule_fetch(
-    miner: (String, String),
-    threads: Option<u32>,
-    tx: Sender<MiningResultExt>,
-    deadline_diff: Option<u64>,
-) -> Result<String, JobSchedulerError> {
-    let sched = JobScheduler::new().await?;
-    let check_job = Job::new_async("1/5 * * * * *", move |_uuid, _l| {
-        let _tx = tx.clone();
-        let _miner = miner.clone();
-        let _threads = threads.clone();
-        Box::pin(async move {
-            match fetch_blocks(deadline_diff).await {
-                Ok((hash, bitwork, dead_line)) => {
-                    let _ = multi_run_v3(
-                        bitwork,
-                        hash.clone(),
-                        hex::decode(_miner.1).unwrap(),
-                        _threads,
-                        dead_line,
-                    )
-                    .await
-                    .map_or_else(
-                        |e| {
-                            error!("Mined Error: {}", e);
-                        },
-                        |r| {
-                            _tx.send(MiningResultExt {
-                                result: MiningResultType::Cpu(r),
-                                remote_hash: hash.clone(),
-                                dead_line,
-                            })
-                            .unwrap();
-                        },
-                    );
-                }
-                Err(e) => {
-                    info!("{:?}", e);
-                }
-            }
-        })
-    })?;
-    let id = sched.add(check_job).await?;
-    sched.start().await?;
-
-    // Wait while the jobs run
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    Ok(id.to_string())
+fn parse_secs(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err("expected a positive number of seconds".to_string()),
+    }
 }
 
-async fn register(
-    dod: &str,
-    siwb: &str,
-    ic_network: &str,
-    wif: &str,
-) -> Result<(String, String), String> {
-    let mut miner = MINER.lock().await;
-
-    miner.set_dod_canister(Principal::from_text(dod).unwrap());
-    miner.set_siwb_canister(Principal::from_text(siwb).unwrap());
-    miner.set_ic_network(Some(ic_network.to_string()));
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    let (btc_address, btc_pubkey) = get_p2tr_from_wif(wif, ic_network);
+/// Runs once mining has stopped: waits for the submissions in flight and saves
+/// whatever is left unanswered. Returns the exit code.
+async fn shutdown(engine: &MinerEngine) -> i32 {
+    let pending = engine.pending_submissions();
+    if !pending.is_empty() {
+        info!(
+            "Waiting up to {}s for {} submissions in flight",
+            SUBMIT_DRAIN_TIMEOUT.as_secs(),
+            pending.len()
+        );
+    }
+    let left = engine.drain_submissions(SUBMIT_DRAIN_TIMEOUT).await;
+    if left.is_empty() {
+        info!("Shut down cleanly");
+        return 0;
+    }
 
-    match miner
-        .connect(wif.to_string(), btc_address.clone(), btc_pubkey.clone())
-        .await
-    {
+    match save_pending(UNSUBMITTED_FILE, &left) {
         Ok(_) => {
-            info!("Connected to the miner successfully");
-            match miner
-                .register_miner(btc_address.clone(), btc_pubkey.clone())
-                .await
-            {
-                Ok(_) => {
-                    info!("Miner registered successfully");
-                    Ok((btc_address.clone(), btc_pubkey.clone()))
-                }
-                Err(e) => {
-                    error!("Error registering the miner: {}", e);
-                    Err(e)
-                }
-            }
+            error!(
+                "{} solutions were not submitted, saved to {}",
+                left.len(),
+                UNSUBMITTED_FILE
+            );
+            EXIT_UNSUBMITTED
         }
         Err(e) => {
-            error!("Error connecting to the miner: {}", e);
-            Err(e)
+            error!("Failed to save the unsubmitted solutions: {}", e);
+            for p in left.iter() {
+                error!("Unsubmitted: {:?}", p);
+            }
+            EXIT_UNSUBMITTED_LOST
         }
     }
 }
 
-async fn fetch_blocks(deadline_diff: Option<u64>) -> Result<(Vec<u8>, Bitwork, u128), String> {
-    info!("should fetch blocks?");
-    let miner = MINER.lock().await;
-    let mut latest_block = LATEST_BLOCK.lock().await;
-    let running = RUNNING.lock().await;
-
-    if running.clone() == true {
-        return Err("Already running".to_string());
+async fn log_events(mut events: broadcast::Receiver<MinerEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(MinerEvent::Error(e)) => error!("{}", e),
+            Ok(MinerEvent::Submitted {
+                height,
+                block_height,
+            }) => info!(
+                "Submitted the solution for block {} ({})",
+                height, block_height
+            ),
+            Ok(MinerEvent::SolutionFound { height, result }) => {
+                info!(
+                    "Found a solution for block {}: {}",
+                    height,
+                    result.mining_result()
+                )
+            }
+            Ok(event) => info!("{:?}", event),
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
     }
+}
 
-    if miner.is_miner() {
-        match miner.get_last_block().await {
-            Ok(b) => {
-                if b.is_none() {
-                    Err("No blocks found".to_string())
-                } else {
-                    let (num, block) = b.unwrap();
-                    if latest_block.is_none()
-                        || (latest_block.is_some() && num > latest_block.unwrap())
-                    {
-                        *latest_block = Some(num);
+async fn bench(args: BenchArgs) {
+    let duration = Duration::from_secs(args.seconds.max(1));
+    println!("threads  hashes        hashrate");
+    let results = run_bench(args.max_threads, duration, |r| {
+        println!(
+            "{:<8} {:<13} {}",
+            r.threads,
+            r.hashes,
+            format_hashrate(r.rate)
+        );
+    })
+    .await;
 
-                        let dead_line = (block.next_block_time
-                            - deadline_diff.unwrap_or(5_000_000_000))
-                            as u128;
-                        let hash = block.hash.clone();
-                        let bitwork = block.difficulty.clone();
-                        Ok((hash, bitwork, dead_line))
-                    } else {
-                        Err("No new blocks found".to_string())
-                    }
+    match recommend_threads(&results) {
+        Some(threads) => {
+            println!("Recommended: --threads {}", threads);
+            if args.save {
+                match save_threads(".env", threads) {
+                    Ok(_) => println!("Saved {}={} to .env", THREADS_ENV, threads),
+                    Err(e) => println!("Failed to save to .env: {}", e),
                 }
             }
-            Err(e) => Err(e),
         }
-    } else {
-        Err("Not a miner".to_string())
+        None => println!("No results"),
     }
 }
-
-fn submit(
-    miner_tuple: (String, String),
-    wif: String,
-    mining_result: MiningResultExt,
-    cycles_price: u128,
-) {
-    let _remote_hash = mining_result.remote_hash.clone();
-    let _miner_tuple = miner_tuple.clone();
-    let _wif = wif.clone();
-    let _mining_result = mining_result.result.clone();
-    let _cycles_price = cycles_price;
-    let _dead_line = mining_result.dead_line.clone();
-
-    let _ = tokio::spawn(async move {
-        let miner = MINER.lock().await;
-        let raw_pub = hex::decode(_miner_tuple.1).unwrap();
-
-        let _ = miner
-            .submit_result(
-                _remote_hash,
-                raw_pub,
-                _miner_tuple.0,
-                _wif,
-                _mining_result,
-                _cycles_price,
-            )
-            .await;
-    });
-}
@@ -0,0 +1,262 @@
+use crate::types::SubmitError;
+use dod_utils::types::MinerSubmitResponse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use sysinfo::{Pid, ProcessRefreshKind, System};
+
+/// Content type of [`Metrics::render`], the Prometheus text format.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const SUBMISSION_OUTCOMES: [&str; 3] = ["accepted", "rejected", "failed"];
+
+#[derive(Debug, Clone, Default)]
+struct CallStats {
+    count: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+/// Counters of one miner, rendered by `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    hashes: AtomicU64,
+    rounds_started: AtomicU64,
+    rounds_expired: AtomicU64,
+    solutions_found: AtomicU64,
+    submissions: [AtomicU64; 3],
+    calls: Mutex<BTreeMap<String, CallStats>>,
+    system: Mutex<Option<System>>,
+}
+
+/// How the canister took a submission. Errors of the call itself or of decoding
+/// its answer, and solutions that failed the check before sending, are `failed`.
+/// Errors returned by the canister are `rejected`.
+pub fn submission_outcome(res: &Result<MinerSubmitResponse, SubmitError>) -> &'static str {
+    match res {
+        Ok(_) => "accepted",
+        Err(SubmitError::Invalid(_)) | Err(SubmitError::Call(_)) => "failed",
+        Err(SubmitError::Rejected(_)) => "rejected",
+    }
+}
+
+impl Metrics {
+    /// Hashes of a round that is over, the running one is passed to `render`.
+    pub fn add_hashes(&self, hashes: u64) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    pub fn round_started(&self) {
+        self.rounds_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn round_expired(&self) {
+        self.rounds_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn solution_found(&self) {
+        self.solutions_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn submitted(&self, res: &Result<MinerSubmitResponse, SubmitError>) {
+        let outcome = submission_outcome(res);
+        if let Some(i) = SUBMISSION_OUTCOMES.iter().position(|o| *o == outcome) {
+            self.submissions[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records one canister call, `method` being the canister method name.
+    pub fn observe_call(&self, method: &str, elapsed: Duration, ok: bool) {
+        let mut calls = self.calls.lock().unwrap();
+        let stats = calls.entry(method.to_string()).or_default();
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+        if !ok {
+            stats.errors += 1;
+        }
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// The metrics in the Prometheus text format. `round_hashes` are the hashes
+    /// of the running round, added to `dod_miner_hashes_total`.
+    pub fn render(&self, round_hashes: u64, gauges: &[(&str, &str, f64)]) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "dod_miner_hashes_total",
+            "Hashes computed.",
+            self.hashes() + round_hashes,
+        );
+        counter(
+            &mut out,
+            "dod_miner_rounds_started_total",
+            "Mining rounds started.",
+            self.rounds_started.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "dod_miner_rounds_expired_total",
+            "Mining rounds that reached their deadline without a solution.",
+            self.rounds_expired.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "dod_miner_solutions_found_total",
+            "Solutions found.",
+            self.solutions_found.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "dod_miner_submissions_total",
+            "counter",
+            "Solutions submitted, by outcome.",
+        );
+        for (i, outcome) in SUBMISSION_OUTCOMES.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "dod_miner_submissions_total{{outcome=\"{}\"}} {}",
+                outcome,
+                self.submissions[i].load(Ordering::Relaxed)
+            );
+        }
+
+        let calls = self.calls.lock().unwrap().clone();
+        header(
+            &mut out,
+            "dod_miner_canister_call_seconds",
+            "summary",
+            "Latency of canister calls, by method.",
+        );
+        for (method, stats) in calls.iter() {
+            let _ = writeln!(
+                out,
+                "dod_miner_canister_call_seconds_sum{{method=\"{}\"}} {}",
+                method, stats.seconds
+            );
+            let _ = writeln!(
+                out,
+                "dod_miner_canister_call_seconds_count{{method=\"{}\"}} {}",
+                method, stats.count
+            );
+        }
+        header(
+            &mut out,
+            "dod_miner_canister_call_errors_total",
+            "counter",
+            "Failed canister calls, by method.",
+        );
+        for (method, stats) in calls.iter() {
+            let _ = writeln!(
+                out,
+                "dod_miner_canister_call_errors_total{{method=\"{}\"}} {}",
+                method, stats.errors
+            );
+        }
+
+        for (name, help, value) in gauges {
+            gauge(&mut out, name, help, *value);
+        }
+
+        if let Some(cpu) = self.process_cpu_usage() {
+            gauge(
+                &mut out,
+                "dod_miner_process_cpu_usage_percent",
+                "Cpu used by the process since the last scrape, 100 per core.",
+                cpu as f64,
+            );
+        }
+        if let Some(mem) = memory_stats::memory_stats() {
+            gauge(
+                &mut out,
+                "dod_miner_process_resident_memory_bytes",
+                "Resident memory of the process.",
+                mem.physical_mem as f64,
+            );
+            gauge(
+                &mut out,
+                "dod_miner_process_virtual_memory_bytes",
+                "Virtual memory of the process.",
+                mem.virtual_mem as f64,
+            );
+        }
+        out
+    }
+
+    /// Usage since the previous call, so the first one after start reports 0.
+    fn process_cpu_usage(&self) -> Option<f32> {
+        let pid = Pid::from_u32(std::process::id());
+        let mut system = self.system.lock().unwrap();
+        let system = system.get_or_insert_with(System::new);
+        system.refresh_process_specifics(pid, ProcessRefreshKind::new().with_cpu());
+        system.process(pid).map(|p| p.cpu_usage())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::{submission_outcome, Metrics};
+    use crate::types::SubmitError;
+    use dod_utils::types::MinerSubmitResponse;
+    use std::time::Duration;
+
+    #[test]
+    fn classifies_submissions() {
+        let accepted = Ok(MinerSubmitResponse {
+            block_height: 1,
+            cycles_price: 0,
+        });
+        assert_eq!(submission_outcome(&accepted), "accepted");
+        let call = SubmitError::Call("Error miner_submit_hash: timeout".to_string());
+        assert_eq!(submission_outcome(&Err(call)), "failed");
+        let invalid = SubmitError::Invalid("Invalid solution, commit txid".to_string());
+        assert_eq!(submission_outcome(&Err(invalid)), "failed");
+        // however the canister words it
+        let rejected = SubmitError::Rejected("Error decoding the psbt".to_string());
+        assert_eq!(submission_outcome(&Err(rejected)), "rejected");
+    }
+
+    #[test]
+    fn renders_counters() {
+        let metrics = Metrics::default();
+        metrics.add_hashes(100);
+        metrics.round_started();
+        metrics.submitted(&Err(SubmitError::Rejected(
+            "Block already mined".to_string(),
+        )));
+        metrics.observe_call("get_last_block", Duration::from_millis(250), true);
+        metrics.observe_call("get_last_block", Duration::from_millis(750), false);
+
+        let out = metrics.render(20, &[("dod_miner_paused", "Paused.", 1.0)]);
+        assert!(out.contains("\ndod_miner_hashes_total 120\n"));
+        assert!(out.contains("\ndod_miner_rounds_started_total 1\n"));
+        assert!(out.contains("dod_miner_submissions_total{outcome=\"rejected\"} 1\n"));
+        assert!(out.contains("dod_miner_submissions_total{outcome=\"accepted\"} 0\n"));
+        assert!(out.contains("dod_miner_canister_call_seconds_sum{method=\"get_last_block\"} 1\n"));
+        assert!(
+            out.contains("dod_miner_canister_call_seconds_count{method=\"get_last_block\"} 2\n")
+        );
+        assert!(out.contains("dod_miner_canister_call_errors_total{method=\"get_last_block\"} 1\n"));
+        assert!(out.contains("# TYPE dod_miner_paused gauge\ndod_miner_paused 1\n"));
+    }
+}
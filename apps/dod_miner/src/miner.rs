@@ -1,22 +1,31 @@
-use crate::state::RUNNING;
+use crate::affinity::WorkerPlacement;
+use crate::scheduler::NonceScheduler;
+use crate::telemetry::{format_hashrate, MiningProgress, RoundMonitor, REPORT_INTERVAL};
 use crate::types::{MiningResult, ThreadResult};
 
 use dod_cpu::threads::{get_available_threads, get_multi_progress};
 use dod_cpu::tx::{create_dod_tx, CreateDodTxDefault};
 
-use dod_utils::bitwork::Bitwork;
+use dod_utils::bitwork::{Bitwork, BitworkTarget};
+use dod_utils::hasher::HashBackend;
+use dod_utils::mine::{mine_bitwork_range, CancelToken};
 
 use flume::Sender;
-use log::info;
+use indicatif::ProgressDrawTarget;
+use log::{info, warn};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Instant, SystemTime};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn multi_run_v3(
     bitwork: Bitwork,
     remote_hash: Vec<u8>,
     raw_pubkey: Vec<u8>,
     threads: Option<u32>,
     dead_line: u128,
+    cancel: CancelToken,
+    monitor: RoundMonitor,
+    placement: WorkerPlacement,
 ) -> Result<MiningResult, String> {
     let mut thread_available = get_available_threads();
     thread_available = if threads.is_some() {
@@ -25,32 +34,81 @@ pub async fn multi_run_v3(
         thread_available
     };
 
-    let mut running = RUNNING.lock().await;
-
-    info!("Running {} CPU threads", thread_available);
+    info!(
+        "Running {} CPU threads, sha256 backend: {}",
+        thread_available,
+        HashBackend::detect()
+    );
 
-    *running = true;
+    // waiting on the threads blocks, keep it off the runtime workers
+    tokio::task::spawn_blocking(move || {
+        run_round(
+            bitwork,
+            remote_hash,
+            raw_pubkey,
+            thread_available,
+            dead_line,
+            cancel,
+            monitor,
+            placement,
+        )
+    })
+    .await
+    .unwrap_or_else(|e| Err(format!("Mining round failed: {}", e)))
+}
 
-    let (_, _, tx, rx) = get_multi_progress::<ThreadResult>();
+#[allow(clippy::too_many_arguments)]
+fn run_round(
+    bitwork: Bitwork,
+    remote_hash: Vec<u8>,
+    raw_pubkey: Vec<u8>,
+    thread_available: u32,
+    dead_line: u128,
+    cancel: CancelToken,
+    monitor: RoundMonitor,
+    placement: WorkerPlacement,
+) -> Result<MiningResult, String> {
+    // a bitwork the canister got wrong fails the round, not every worker on its own
+    let target = BitworkTarget::new(&remote_hash, &bitwork)
+        .map_err(|e| format!("{} {}.{}", e, bitwork.pre, bitwork.post_hex))?;
+    let (mp, sty, tx, rx) = get_multi_progress::<ThreadResult>();
+    if !monitor.progress_bars {
+        mp.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let progress = MiningProgress::new(&mp, &sty, thread_available, bitwork.expected_hashes());
 
     let _start_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_millis();
+    // one time for the whole round, so (nonce, counter) identifies every candidate
+    let time = (_start_time / 1000) as u32;
+    let mut progress = progress.with_monitor(monitor, time as u64);
+    let scheduler = NonceScheduler::default();
 
+    let mut handles = Vec::with_capacity(thread_available as usize);
     for i in 0..thread_available {
         let _tx = tx.clone();
-        let _bitwork = bitwork.clone();
+        let _target = target.clone();
         let _remote_hash = remote_hash.clone();
         let _raw_pubkey = raw_pubkey.clone();
         let _dead_line = dead_line.clone();
+        let _scheduler = scheduler.clone();
+        let _cancel = cancel.clone();
+        let _placement = placement.clone();
 
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
+            if let Err(e) = _placement.apply(i) {
+                warn!("Worker {}: {}", i, e);
+            }
             let res = sub_task_v3(
                 _remote_hash,
                 _raw_pubkey,
-                _bitwork,
+                _target,
                 _dead_line,
+                time,
+                _scheduler,
+                _cancel,
                 _tx.clone(),
                 i,
             );
@@ -59,27 +117,31 @@ pub async fn multi_run_v3(
                 Ok(_) => {}
                 Err(_) => {}
             }
-        });
+        }));
     }
+    // only the workers hold senders now, so `rx` ends once they have all exited
+    drop(tx);
 
     let mut ex: Option<MiningResult> = None;
-    let mut _expired = false;
-    // let mut nonces = vec![0u64; thread_available as usize];
 
     for v in rx.iter() {
-        if v.expired || v.res.is_some() {
+        progress.update(&v);
+        if v.res.is_some() {
             ex = v.res.clone();
-            drop(tx);
+            // stop the losing threads right away instead of letting them run to the deadline
+            cancel.cancel();
             break;
         }
-        // nonces[v.index as usize] = v.generated_nonce as u64;
-
-        _expired = v.expired;
     }
 
-    // TODO: this is a hack to make sure all threads are done
-    thread::sleep(Duration::from_millis(300));
-    // tokio::time::sleep(Duration::from_millis(300)).await;
+    for handle in handles {
+        let _ = handle.join();
+    }
+    // collect the final counts of the threads that were stopped
+    for v in rx.try_iter() {
+        progress.update(&v);
+    }
+    progress.finish();
 
     let mut _used_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -91,110 +153,142 @@ pub async fn multi_run_v3(
         _used_time = 1;
     }
     if ex.is_some() {
-        *running = false;
-        info!("Mining completed in {}ms", _used_time);
+        info!(
+            "Mining completed in {}ms, {} hashes searched at {}",
+            _used_time,
+            scheduler.hashes(),
+            format_hashrate(progress.meter().rate())
+        );
         Ok(ex.unwrap())
+    } else if cancel.is_cancelled() {
+        info!(
+            "Mining cancelled in {}ms, {} hashes searched",
+            _used_time,
+            scheduler.hashes()
+        );
+        Err("Cancelled".to_string())
     } else {
-        *running = false;
-        info!("Mining exited in {}ms", _used_time);
+        info!(
+            "Mining exited in {}ms, {} hashes searched",
+            _used_time,
+            scheduler.hashes()
+        );
         Err("Exited on deadline".to_string())
     }
 
     // Ok("".to_string())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn sub_task_v3(
     remote_hash: Vec<u8>,
     raw_pubkey: Vec<u8>,
-    bitwork: Bitwork,
+    target: BitworkTarget,
     dead_line: u128,
+    time: u32,
+    scheduler: NonceScheduler,
+    cancel: CancelToken,
     _tx: Sender<ThreadResult>,
     index: u32,
 ) -> ThreadResult {
-    #[allow(unused_assignments)]
     let mut ret = ThreadResult {
         res: None,
         generated_nonce: 0,
         expired: true,
         index,
+        hashes: 0,
+        nonce: 0,
     };
-    let time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
-    let (tx, start) = create_dod_tx(
-        CreateDodTxDefault {
-            nonce: index,
-            time,
-            remote_hash: remote_hash.clone(),
-            raw_pubkey,
-        },
-        false,
-    );
 
-    let split_length = if bitwork.pre % 2 != 0 {
-        bitwork.pre + 1
-    } else {
-        bitwork.pre
-    };
+    let backend = HashBackend::detect();
 
-    let actual_pre = hex::encode(remote_hash.clone())
-        .split_at(split_length as usize)
-        .0
-        .to_string();
-
-    loop {
-        match dod_utils::mine::mine_bitwork_with_deadline(
-            tx.clone(),
-            start as usize,
-            1,
-            actual_pre.clone(),
-            bitwork.pre,
-            Some(bitwork.post_hex.clone()),
-            dead_line,
+    // commit tx of the nonce field being searched, rebuilt when a chunk moves to another one
+    let mut nonce: Option<u32> = None;
+    let mut tx = vec![];
+    let mut start = 0usize;
+    let mut hashes = 0u64;
+    let mut last_report = Instant::now();
+
+    while let Some(chunk) = scheduler.next_chunk() {
+        if nonce != Some(chunk.nonce) {
+            let (commit_tx, commit_start) = create_dod_tx(
+                CreateDodTxDefault {
+                    nonce: chunk.nonce,
+                    time,
+                    remote_hash: remote_hash.clone(),
+                    raw_pubkey: raw_pubkey.clone(),
+                },
+                false,
+            );
+            nonce = Some(chunk.nonce);
+            tx = commit_tx;
+            start = commit_start as usize;
+        }
+
+        match mine_bitwork_range(
+            &tx,
+            start,
+            chunk.start..chunk.end,
+            &target,
+            Some(dead_line),
+            Some(&cancel),
+            backend,
         ) {
-            Ok(res) => {
+            Ok(Some(res)) => {
+                scheduler.complete(&chunk, res + 1);
+                hashes += res + 1 - chunk.start;
                 ret = ThreadResult {
                     res: Some(MiningResult {
                         num_bytes: res,
-                        nonce: index,
+                        nonce: chunk.nonce,
                         time,
                     }),
                     generated_nonce: res,
                     expired: false,
                     index,
+                    hashes,
+                    nonce: chunk.nonce,
                 };
                 break;
             }
-            Err(e) => {
-                if e.contains("exceeded the allowed") {
-                    ret = ThreadResult {
-                        res: None,
-                        generated_nonce: u64::MAX - 1,
-                        expired: true,
-                        index,
-                    };
-                    break;
-                } else {
-                    ThreadResult {
+            Ok(None) => {
+                scheduler.complete(&chunk, chunk.end);
+                hashes += chunk.end - chunk.start;
+                if last_report.elapsed() >= REPORT_INTERVAL {
+                    last_report = Instant::now();
+                    let _ = _tx.send(ThreadResult {
                         res: None,
-                        generated_nonce: u64::from_str_radix(e.as_str(), 10).unwrap(),
-                        expired: true,
+                        generated_nonce: chunk.end - 1,
+                        expired: false,
                         index,
-                    };
-                    break;
+                        hashes,
+                        nonce: chunk.nonce,
+                    });
                 }
             }
+            Err(e) => {
+                // deadline or cancellation, `e` is the first counter not tried
+                let reached = e.parse::<u64>().unwrap_or(chunk.start);
+                scheduler.complete(&chunk, reached);
+                hashes += reached - chunk.start;
+                ret.generated_nonce = reached;
+                ret.nonce = chunk.nonce;
+                break;
+            }
         }
     }
-    ret.clone()
+    ret.hashes = hashes;
+    ret
 }
 
 #[cfg(test)]
 mod test {
+    use crate::affinity::WorkerPlacement;
     use crate::miner::multi_run_v3;
+    use crate::telemetry::RoundMonitor;
     use dod_utils::bitwork::Bitwork;
-    use std::time::SystemTime;
+    use dod_utils::mine::CancelToken;
+    use std::time::{Duration, Instant, SystemTime};
 
     #[tokio::test]
     pub async fn multirun() {
@@ -222,8 +316,75 @@ mod test {
                 .unwrap()
                 .as_nanos()
                 + 3_000_000_000u128,
+            CancelToken::new(),
+            RoundMonitor::default(),
+            WorkerPlacement::default(),
         )
         .await;
         println!("{:?}", res);
     }
+
+    #[tokio::test]
+    pub async fn multirun_cancelled() {
+        let remote_hash =
+            hex::decode("98799b250c911fe0df86cd59066e329d93bfb3d35fa57cdd3b243e2a8eec1b45")
+                .unwrap();
+        let raw_pubkey =
+            hex::decode("02aa7360476d762b5a88df8db5ad2aabdf2656c3f64a5a9d3c0962541575916917")
+                .unwrap();
+        let cancel = CancelToken::new();
+        let _cancel = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            _cancel.cancel();
+        });
+
+        let started = Instant::now();
+        let res = multi_run_v3(
+            Bitwork {
+                pre: 32,
+                post_hex: "0".to_string(),
+            },
+            remote_hash,
+            raw_pubkey,
+            Some(4),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+                + 60_000_000_000u128,
+            cancel,
+            RoundMonitor::default(),
+            WorkerPlacement::default(),
+        )
+        .await;
+
+        assert_eq!(res, Err("Cancelled".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    pub async fn multirun_malformed_bitwork() {
+        let res = multi_run_v3(
+            Bitwork {
+                pre: 6,
+                post_hex: "g".to_string(),
+            },
+            vec![0x98; 32],
+            vec![0x02; 33],
+            Some(2),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+                + 60_000_000_000u128,
+            CancelToken::new(),
+            RoundMonitor::default(),
+            WorkerPlacement::default(),
+        )
+        .await;
+
+        // an error of the round, not a deadline without a solution
+        assert_eq!(res, Err("Invalid bitwork 6.g".to_string()));
+    }
 }
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Between blocks when their timing is unknown or the next one is overdue.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Around the expected boundary of the next block.
+pub const DEFAULT_NEAR_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How far either side of `next_block_time` polling is dense.
+pub const POLL_WINDOW: Duration = Duration::from_secs(10);
+/// No poll is put off longer than this, nor is any backoff.
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollConfig {
+    pub interval: Duration,
+    pub near_interval: Duration,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            interval: DEFAULT_POLL_INTERVAL,
+            near_interval: DEFAULT_NEAR_POLL_INTERVAL,
+        }
+    }
+}
+
+impl PollConfig {
+    /// How long to wait before asking for the last block again. Sparse in the
+    /// middle of a block, every `near_interval` within [`POLL_WINDOW`] of
+    /// `next_block_time` (unix nanoseconds), every `interval` once it is overdue
+    /// or unknown. After `errors` failed polls in a row it backs off exponentially
+    /// from `interval` instead.
+    pub fn next_delay(&self, now: u128, next_block_time: Option<u64>, errors: u32) -> Duration {
+        if errors > 0 {
+            return self
+                .interval
+                .saturating_mul(1 << errors.min(16))
+                .min(MAX_POLL_INTERVAL);
+        }
+        let next_block_time = match next_block_time {
+            Some(t) => t as u128,
+            None => return self.interval,
+        };
+        let window = POLL_WINDOW.as_nanos();
+        if now + window < next_block_time {
+            // wake up when the window opens
+            let until = (next_block_time - window - now).min(MAX_POLL_INTERVAL.as_nanos());
+            Duration::from_nanos(until as u64).max(self.near_interval)
+        } else if now <= next_block_time + window {
+            self.near_interval
+        } else {
+            self.interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::polling::{PollConfig, MAX_POLL_INTERVAL, POLL_WINDOW};
+    use std::time::Duration;
+
+    const SEC: u128 = 1_000_000_000;
+
+    #[test]
+    fn dense_around_the_boundary() {
+        let config = PollConfig::default();
+        let next = 1_000 * SEC as u64;
+
+        assert_eq!(
+            config.next_delay(next as u128 - 30 * SEC, Some(next), 0),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            config.next_delay(next as u128 - 300 * SEC, Some(next), 0),
+            MAX_POLL_INTERVAL
+        );
+        assert_eq!(
+            config.next_delay(next as u128 - 5 * SEC, Some(next), 0),
+            config.near_interval
+        );
+        assert_eq!(
+            config.next_delay(next as u128 + 5 * SEC, Some(next), 0),
+            config.near_interval
+        );
+        assert_eq!(
+            config.next_delay(next as u128 + POLL_WINDOW.as_nanos() + SEC, Some(next), 0),
+            config.interval
+        );
+        assert_eq!(config.next_delay(0, None, 0), config.interval);
+    }
+
+    #[test]
+    fn backs_off_on_errors() {
+        let config = PollConfig::default();
+        assert_eq!(config.next_delay(0, None, 1), Duration::from_secs(10));
+        assert_eq!(config.next_delay(0, None, 2), Duration::from_secs(20));
+        assert_eq!(config.next_delay(0, None, 30), MAX_POLL_INTERVAL);
+    }
+}
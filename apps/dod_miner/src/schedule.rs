@@ -0,0 +1,174 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How often a running round checks whether its window has closed.
+pub const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A mining window as written in the config file.
+///
+/// ```toml
+/// [[profiles.mainnet.schedule]]
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// from = "19:00"
+/// to = "07:00"
+/// threads = 4
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Days the window opens on, every day if left out.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Local time, `HH:MM`.
+    pub from: String,
+    /// Local time, `HH:MM`. At or before `from` the window runs past midnight.
+    pub to: String,
+    pub threads: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MiningWindow {
+    pub days: Vec<Weekday>,
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    /// At most the miner's thread count, all of them if `None`.
+    pub threads: Option<u32>,
+}
+
+/// When the miner may mine. Without windows it always may.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub windows: Vec<MiningWindow>,
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("Invalid time {}, expected HH:MM", s))
+}
+
+impl WindowConfig {
+    pub fn window(&self) -> Result<MiningWindow, String> {
+        let days = self
+            .days
+            .iter()
+            .map(|d| {
+                d.parse::<Weekday>()
+                    .map_err(|_| format!("Invalid day {}, expected mon to sun", d))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.threads == Some(0) {
+            return Err("Window threads must be at least 1".to_string());
+        }
+        Ok(MiningWindow {
+            days,
+            from: parse_time(&self.from)?,
+            to: parse_time(&self.to)?,
+            threads: self.threads,
+        })
+    }
+}
+
+impl MiningWindow {
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let day = now.weekday();
+        if self.from < self.to {
+            self.opens_on(day) && time >= self.from && time < self.to
+        } else {
+            // opened the evening before, or opens tonight
+            (self.opens_on(day) && time >= self.from)
+                || (self.opens_on(day.pred()) && time < self.to)
+        }
+    }
+}
+
+impl Schedule {
+    pub fn new(windows: &[WindowConfig]) -> Result<Self, String> {
+        Ok(Schedule {
+            windows: windows
+                .iter()
+                .map(|w| w.window())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// The window open at local time `now`, the first one listed if several are.
+    pub fn active(&self, now: NaiveDateTime) -> Option<&MiningWindow> {
+        self.windows.iter().find(|w| w.contains(now))
+    }
+
+    /// Whether mining is allowed at local time `now`, always without windows.
+    pub fn allows(&self, now: NaiveDateTime) -> bool {
+        self.is_empty() || self.active(now).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::schedule::{Schedule, WindowConfig};
+    use chrono::NaiveDate;
+
+    fn at(day: u32, time: &str) -> chrono::NaiveDateTime {
+        // 2024-01-01 is a monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn weeknights_and_office_hours() {
+        let schedule = Schedule::new(&[
+            WindowConfig {
+                days: vec!["mon", "tue", "wed", "thu", "fri"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                from: "19:00".to_string(),
+                to: "07:00".to_string(),
+                threads: Some(4),
+            },
+            WindowConfig {
+                days: vec!["sat".to_string(), "sun".to_string()],
+                from: "00:00".to_string(),
+                to: "00:00".to_string(),
+                threads: None,
+            },
+        ])
+        .unwrap();
+
+        // monday at noon, business hours
+        assert_eq!(schedule.active(at(1, "12:00")), None);
+        let monday_night = schedule.active(at(1, "23:00")).unwrap();
+        assert_eq!(monday_night.threads, Some(4));
+        // tuesday morning, still monday night's window
+        assert!(schedule.allows(at(2, "06:59")));
+        assert_eq!(schedule.active(at(2, "07:00")), None);
+        // the night from friday runs into saturday, then the weekend is all day
+        assert_eq!(schedule.active(at(6, "03:00")).unwrap().threads, Some(4));
+        assert_eq!(schedule.active(at(7, "12:00")).unwrap().threads, None);
+        // monday morning after the weekend window
+        assert!(!schedule.allows(at(8, "00:30")));
+        assert!(Schedule::default().allows(at(1, "12:00")));
+    }
+
+    #[test]
+    fn rejects_bad_windows() {
+        let window = |days: &str, from: &str| WindowConfig {
+            days: vec![days.to_string()],
+            from: from.to_string(),
+            to: "07:00".to_string(),
+            threads: None,
+        };
+        assert!(Schedule::new(&[window("mon", "19:00")]).is_ok());
+        assert!(Schedule::new(&[window("someday", "19:00")]).is_err());
+        assert!(Schedule::new(&[window("mon", "7pm")]).is_err());
+    }
+}
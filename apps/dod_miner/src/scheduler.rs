@@ -0,0 +1,206 @@
+use dod_utils::mine::RBF_LIMIT;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Counters handed out per chunk, small enough that fast threads keep pulling
+/// new chunks while slow ones are still busy.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1 << 18;
+
+/// Counter range `start..end` of the commit tx built with `DodMining.nonce == nonce`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct NonceChunk {
+    pub nonce: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    nonce: u32,
+    next: u64,
+    exhausted: bool,
+    returned: VecDeque<NonceChunk>,
+    // (nonce, start) -> end, adjacent ranges are merged
+    covered: BTreeMap<(u32, u64), u64>,
+    hashes: u64,
+}
+
+/// Shared queue of nonce-space chunks for the threads mining one block. Threads
+/// pull a chunk whenever they finish one, so faster cores end up covering more of
+/// the space, and no range is handed out twice within the block.
+#[derive(Debug, Clone)]
+pub struct NonceScheduler {
+    chunk_size: u64,
+    state: Arc<Mutex<SchedulerState>>,
+}
+
+impl Default for NonceScheduler {
+    fn default() -> Self {
+        NonceScheduler::new(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl NonceScheduler {
+    pub fn new(chunk_size: u64) -> Self {
+        NonceScheduler {
+            chunk_size: chunk_size.max(1),
+            state: Arc::new(Mutex::new(SchedulerState {
+                next: 1,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Hands out the next unsearched chunk, preferring leftovers of interrupted ones.
+    pub fn next_chunk(&self) -> Option<NonceChunk> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(chunk) = state.returned.pop_front() {
+            return Some(chunk);
+        }
+        if state.exhausted {
+            return None;
+        }
+
+        let start = state.next;
+        let end = start.saturating_add(self.chunk_size).min(RBF_LIMIT);
+        let chunk = NonceChunk {
+            nonce: state.nonce,
+            start,
+            end,
+        };
+
+        if end == RBF_LIMIT {
+            // the counter space of this nonce field is used up, move to the next one
+            match state.nonce.checked_add(1) {
+                Some(nonce) => {
+                    state.nonce = nonce;
+                    state.next = 1;
+                }
+                None => state.exhausted = true,
+            }
+        } else {
+            state.next = end;
+        }
+        Some(chunk)
+    }
+
+    /// Records that `chunk.start..done` has been searched. Whatever is left of the
+    /// chunk goes back to the queue for the next thread asking for work.
+    pub fn complete(&self, chunk: &NonceChunk, done: u64) {
+        let done = done.clamp(chunk.start, chunk.end);
+        let mut state = self.state.lock().unwrap();
+
+        if done < chunk.end {
+            state.returned.push_back(NonceChunk {
+                nonce: chunk.nonce,
+                start: done,
+                end: chunk.end,
+            });
+        }
+        if done == chunk.start {
+            return;
+        }
+        state.hashes += done - chunk.start;
+
+        let mut start = chunk.start;
+        let mut end = done;
+        let before = state
+            .covered
+            .range(..(chunk.nonce, start))
+            .next_back()
+            .map(|(k, v)| (*k, *v));
+        if let Some(((nonce, prev_start), prev_end)) = before {
+            if nonce == chunk.nonce && prev_end == start {
+                state.covered.remove(&(nonce, prev_start));
+                start = prev_start;
+            }
+        }
+        if let Some(next_end) = state.covered.remove(&(chunk.nonce, end)) {
+            end = next_end;
+        }
+        state.covered.insert((chunk.nonce, start), end);
+    }
+
+    /// Total counters searched so far.
+    pub fn hashes(&self) -> u64 {
+        self.state.lock().unwrap().hashes
+    }
+
+    /// Searched ranges, merged where they touch.
+    pub fn covered(&self) -> Vec<NonceChunk> {
+        self.state
+            .lock()
+            .unwrap()
+            .covered
+            .iter()
+            .map(|((nonce, start), end)| NonceChunk {
+                nonce: *nonce,
+                start: *start,
+                end: *end,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scheduler::{NonceChunk, NonceScheduler};
+    use dod_utils::mine::RBF_LIMIT;
+
+    #[test]
+    fn chunks_do_not_overlap() {
+        let scheduler = NonceScheduler::new(100);
+        let a = scheduler.next_chunk().unwrap();
+        let b = scheduler.next_chunk().unwrap();
+        assert_eq!(
+            a,
+            NonceChunk {
+                nonce: 0,
+                start: 1,
+                end: 101
+            }
+        );
+        assert_eq!(b.start, a.end);
+
+        scheduler.complete(&b, b.end);
+        scheduler.complete(&a, a.end);
+        assert_eq!(scheduler.hashes(), 200);
+        assert_eq!(
+            scheduler.covered(),
+            vec![NonceChunk {
+                nonce: 0,
+                start: 1,
+                end: 201
+            }]
+        );
+    }
+
+    #[test]
+    fn interrupted_chunk_is_resumed() {
+        let scheduler = NonceScheduler::new(100);
+        let a = scheduler.next_chunk().unwrap();
+        scheduler.complete(&a, 40);
+
+        let resumed = scheduler.next_chunk().unwrap();
+        assert_eq!(
+            resumed,
+            NonceChunk {
+                nonce: 0,
+                start: 40,
+                end: 101
+            }
+        );
+        assert_eq!(scheduler.next_chunk().unwrap().start, 101);
+        assert_eq!(scheduler.hashes(), 39);
+    }
+
+    #[test]
+    fn moves_to_next_nonce_field() {
+        let scheduler = NonceScheduler::new(RBF_LIMIT);
+        let a = scheduler.next_chunk().unwrap();
+        let b = scheduler.next_chunk().unwrap();
+        assert_eq!((a.nonce, a.start, a.end), (0, 1, RBF_LIMIT));
+        assert_eq!((b.nonce, b.start), (1, 1));
+    }
+}
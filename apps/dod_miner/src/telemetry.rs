@@ -0,0 +1,304 @@
+use crate::threads::SharedThreads;
+use crate::types::{ThreadResult, ThreadStatus, WorkerStatus};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a mining thread reports its hash count while it has not found anything.
+pub const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the running hashrate of a block is written to the log.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn hashrate(hashes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        0.0
+    } else {
+        hashes as f64 / secs
+    }
+}
+
+pub fn format_hashrate(rate: f64) -> String {
+    let units = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s"];
+    let mut rate = rate;
+    let mut unit = 0;
+    while rate >= 1000.0 && unit < units.len() - 1 {
+        rate /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", rate, units[unit])
+}
+
+/// Hashes tried by each mining thread during one block.
+#[derive(Debug, Clone)]
+pub struct HashrateMeter {
+    started: Instant,
+    threads: Vec<u64>,
+}
+
+impl HashrateMeter {
+    pub fn new(threads: u32) -> Self {
+        HashrateMeter {
+            started: Instant::now(),
+            threads: vec![0; threads as usize],
+        }
+    }
+
+    /// Workers report the total they have tried so far, not a delta.
+    pub fn record(&mut self, index: u32, hashes: u64) {
+        if let Some(h) = self.threads.get_mut(index as usize) {
+            *h = (*h).max(hashes);
+        }
+    }
+
+    pub fn thread_hashes(&self, index: u32) -> u64 {
+        self.threads.get(index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.threads.iter().sum()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn thread_rate(&self, index: u32) -> f64 {
+        hashrate(self.thread_hashes(index), self.elapsed())
+    }
+
+    pub fn rate(&self) -> f64 {
+        hashrate(self.hashes(), self.elapsed())
+    }
+}
+
+/// Where a round publishes its progress besides the log and the bars.
+#[derive(Debug, Clone, Default)]
+pub struct RoundMonitor {
+    /// Total hashes tried by the round.
+    pub counter: Option<Arc<AtomicU64>>,
+    /// Each thread is set to `Mining` while the round runs, and back to `Idle` after.
+    pub workers: Option<SharedThreads>,
+    pub height: Option<u64>,
+    /// Draw the bars on the terminal, they are kept hidden otherwise.
+    pub progress_bars: bool,
+}
+
+/// Live per-thread progress of one block, drawn with the `MultiProgress` from
+/// `get_multi_progress` and logged every [`LOG_INTERVAL`]. Bars run up to the
+/// hashes the bitwork is expected to take.
+pub struct MiningProgress {
+    meter: HashrateMeter,
+    bars: Vec<ProgressBar>,
+    total: ProgressBar,
+    last_log: Instant,
+    monitor: RoundMonitor,
+    started_at: u64,
+}
+
+impl MiningProgress {
+    pub fn new(mp: &MultiProgress, sty: &ProgressStyle, threads: u32, expected: f64) -> Self {
+        let expected = expected.min(u64::MAX as f64) as u64;
+        let total = mp.add(ProgressBar::new(expected).with_style(sty.clone()));
+        let bars = (0..threads)
+            .map(|i| {
+                let bar = mp.add(
+                    ProgressBar::new(expected / threads.max(1) as u64).with_style(sty.clone()),
+                );
+                bar.set_message(format!("thread {}", i));
+                bar
+            })
+            .collect();
+
+        MiningProgress {
+            meter: HashrateMeter::new(threads),
+            bars,
+            total,
+            last_log: Instant::now(),
+            monitor: RoundMonitor::default(),
+            started_at: 0,
+        }
+    }
+
+    /// Registers every thread with `monitor.workers` as mining since `started_at`.
+    pub fn with_monitor(mut self, monitor: RoundMonitor, started_at: u64) -> Self {
+        if let Some(workers) = monitor.workers.as_ref() {
+            let mut workers = workers.lock().unwrap();
+            for i in 0..self.bars.len() as u32 {
+                workers.set_t(
+                    i,
+                    ThreadStatus::Mining(WorkerStatus {
+                        height: monitor.height,
+                        nonce: 0,
+                        started_at,
+                        hashes: 0,
+                    }),
+                );
+            }
+        }
+        self.monitor = monitor;
+        self.started_at = started_at;
+        self
+    }
+
+    pub fn meter(&self) -> &HashrateMeter {
+        &self.meter
+    }
+
+    pub fn update(&mut self, res: &ThreadResult) {
+        self.meter.record(res.index, res.hashes);
+
+        if let Some(bar) = self.bars.get(res.index as usize) {
+            bar.set_position(self.meter.thread_hashes(res.index));
+            bar.set_message(format!(
+                "thread {} {}",
+                res.index,
+                format_hashrate(self.meter.thread_rate(res.index))
+            ));
+        }
+        self.total.set_position(self.meter.hashes());
+        if let Some(counter) = self.monitor.counter.as_ref() {
+            counter.store(self.meter.hashes(), Ordering::Relaxed);
+        }
+        if let Some(workers) = self.monitor.workers.as_ref() {
+            workers.lock().unwrap().set_t(
+                res.index,
+                ThreadStatus::Mining(WorkerStatus {
+                    height: self.monitor.height,
+                    nonce: res.nonce,
+                    started_at: self.started_at,
+                    hashes: self.meter.thread_hashes(res.index),
+                }),
+            );
+        }
+        self.total
+            .set_message(format!("total {}", format_hashrate(self.meter.rate())));
+
+        if self.last_log.elapsed() >= LOG_INTERVAL {
+            self.last_log = Instant::now();
+            info!(
+                "Mining at {}, {} hashes tried",
+                format_hashrate(self.meter.rate()),
+                self.meter.hashes()
+            );
+        }
+    }
+
+    /// Clears the bars and logs how each thread performed over the block.
+    pub fn finish(&self) {
+        for bar in self.bars.iter() {
+            bar.finish_and_clear();
+        }
+        self.total.finish_and_clear();
+        if let Some(workers) = self.monitor.workers.as_ref() {
+            let mut workers = workers.lock().unwrap();
+            for i in 0..self.bars.len() as u32 {
+                workers.set_t(i, ThreadStatus::Idle);
+            }
+        }
+
+        for i in 0..self.bars.len() as u32 {
+            info!(
+                "Thread {}: {} hashes, {}",
+                i,
+                self.meter.thread_hashes(i),
+                format_hashrate(self.meter.thread_rate(i))
+            );
+        }
+        info!(
+            "Total: {} hashes in {}ms, {}",
+            self.meter.hashes(),
+            self.meter.elapsed().as_millis(),
+            format_hashrate(self.meter.rate())
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::telemetry::{
+        format_hashrate, hashrate, HashrateMeter, MiningProgress, RoundMonitor,
+    };
+    use crate::threads::ThreadsManager;
+    use crate::types::{ThreadResult, ThreadStatus, WorkerStatus};
+    use indicatif::{MultiProgress, ProgressDrawTarget, ProgressStyle};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn meter_keeps_latest_totals() {
+        let mut meter = HashrateMeter::new(2);
+        meter.record(0, 100);
+        meter.record(1, 50);
+        meter.record(0, 300);
+        // a late progress report must not move a thread backwards
+        meter.record(1, 20);
+        meter.record(5, 1000);
+
+        assert_eq!(meter.thread_hashes(0), 300);
+        assert_eq!(meter.thread_hashes(1), 50);
+        assert_eq!(meter.hashes(), 350);
+    }
+
+    #[test]
+    fn formats_hashrate() {
+        assert_eq!(hashrate(3_000_000, Duration::from_secs(2)), 1_500_000.0);
+        assert_eq!(hashrate(10, Duration::ZERO), 0.0);
+        assert_eq!(format_hashrate(950.0), "950.00 H/s");
+        assert_eq!(format_hashrate(3_540_000.0), "3.54 MH/s");
+    }
+
+    #[test]
+    fn progress_updates_worker_status() {
+        let workers = Arc::new(Mutex::new(ThreadsManager::default()));
+        let mp = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let mut progress = MiningProgress::new(&mp, &ProgressStyle::default_bar(), 2, 1000.0)
+            .with_monitor(
+                RoundMonitor {
+                    counter: None,
+                    workers: Some(workers.clone()),
+                    height: Some(7),
+                    progress_bars: false,
+                },
+                100,
+            );
+        assert_eq!(
+            workers.lock().unwrap().get_t(1),
+            Some(ThreadStatus::Mining(WorkerStatus {
+                height: Some(7),
+                nonce: 0,
+                started_at: 100,
+                hashes: 0,
+            }))
+        );
+
+        progress.update(&ThreadResult {
+            res: None,
+            generated_nonce: 10,
+            expired: false,
+            index: 1,
+            hashes: 42,
+            nonce: 3,
+        });
+        assert_eq!(
+            workers.lock().unwrap().get_t(1),
+            Some(ThreadStatus::Mining(WorkerStatus {
+                height: Some(7),
+                nonce: 3,
+                started_at: 100,
+                hashes: 42,
+            }))
+        );
+
+        progress.finish();
+        assert!(workers
+            .lock()
+            .unwrap()
+            .get_all_ts()
+            .values()
+            .all(|s| *s == ThreadStatus::Idle));
+    }
+}
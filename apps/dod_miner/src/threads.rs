@@ -1,8 +1,12 @@
-use crate::state::THREADS;
 use crate::types::ThreadStatus;
 use dod_cpu::threads::get_available_threads;
 use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
+/// A `ThreadsManager` the mining round updates while the engine reads it.
+pub type SharedThreads = Arc<Mutex<ThreadsManager>>;
+
+#[derive(Debug)]
 pub struct ThreadsManager {
     pub max_threads: u32,
     pub t_map: BTreeMap<u32, ThreadStatus>,
@@ -56,34 +60,6 @@ impl ThreadsManager {
         self.t_map.clone()
     }
 }
-pub async fn tm_get_all_ts() -> Vec<u32> {
-    let tm = THREADS.lock().await;
-    let mut res = tm.get_all_ts().keys().cloned().collect::<Vec<u32>>();
-    res.sort();
-    res
-}
-
-pub async fn tm_get_available_threads() -> u32 {
-    let tm = THREADS.lock().await;
-    tm.max_threads
-}
-pub async fn tm_get_idle_ts() -> Vec<u32> {
-    let tm = THREADS.lock().await;
-    let mut res = tm.get_idle_ts();
-    res.sort();
-    res
-}
-
-pub async fn tm_set_t(t: u32, status: ThreadStatus) -> Option<ThreadStatus> {
-    let mut tm = THREADS.lock().await;
-    tm.set_t(t, status)
-}
-
-#[allow(dead_code)]
-pub async fn tm_get_t(t: u32) -> Option<ThreadStatus> {
-    let tm = THREADS.lock().await;
-    tm.get_t(t)
-}
 
 #[test]
 fn test_get_threads() {
@@ -0,0 +1,382 @@
+use crate::engine::{EngineStatus, MinerEngine, RoundOutcome, RoundRecord};
+use crate::telemetry::{format_hashrate, hashrate};
+use crate::types::ThreadStatus;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{LevelFilter, Record};
+use log4rs::append::file::FileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How often the dashboard is redrawn.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+/// Log lines shown under the dashboard.
+pub const LOG_TAIL_LINES: usize = 10;
+/// Where the log goes in full while the dashboard only shows its tail.
+pub const DASHBOARD_LOG_FILE: &str = "log/dod_miner.log";
+
+const RECENT_ROUNDS: usize = 5;
+
+/// A log4rs appender keeping the latest lines for the dashboard.
+#[derive(Debug, Clone)]
+pub struct LogTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogTail {
+    pub fn new(capacity: usize) -> Self {
+        LogTail {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Append for LogTail {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        self.push(format!(
+            "{} {:<5} {}",
+            chrono::Local::now().format("%H:%M:%S"),
+            record.level(),
+            record.args()
+        ));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs to `tail` and to [`DASHBOARD_LOG_FILE`] instead of the console, which
+/// the dashboard draws over.
+pub fn init_logging(tail: &LogTail) -> Result<(), String> {
+    let file = FileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d} {l} {m}{n}")))
+        .build(DASHBOARD_LOG_FILE)
+        .map_err(|e| e.to_string())?;
+    let config = Config::builder()
+        .appender(Appender::builder().build("tail", Box::new(tail.clone())))
+        .appender(Appender::builder().build("file", Box::new(file)))
+        .build(
+            Root::builder()
+                .appender("tail")
+                .appender("file")
+                .build(LevelFilter::Info),
+        )
+        .map_err(|e| e.to_string())?;
+    log4rs::init_config(config)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// `nanos` from now as `1m05s`, or `passed` once it is behind.
+pub fn format_countdown(nanos: i128) -> String {
+    if nanos < 0 {
+        return "passed".to_string();
+    }
+    let secs = nanos / 1_000_000_000;
+    if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn format_cycles(cycles: u128) -> String {
+    format!("{}T", cycles as f64 / 1e12)
+}
+
+/// Block, bitwork, remote hash and countdowns.
+pub fn header_lines(status: &EngineStatus, now_nanos: u128) -> Vec<String> {
+    let state = if status.stopped {
+        "stopping"
+    } else if status.paused {
+        "paused"
+    } else if status.off_schedule {
+        "outside the schedule"
+    } else if status.running {
+        "mining"
+    } else if !status.registered {
+        "registering"
+    } else {
+        "waiting for a block"
+    };
+    let threads = match status.thread_limit {
+        Some(limit) if limit < status.max_threads => {
+            format!("{} (throttled to {})", status.max_threads, limit)
+        }
+        _ => status.max_threads.to_string(),
+    };
+    let mut lines = vec![format!(
+        "dod miner  {}  {}  threads {}  cycles price {}",
+        status.btc_address.as_deref().unwrap_or("-"),
+        state,
+        threads,
+        format_cycles(status.cycles_price)
+    )];
+
+    match status.round.as_ref() {
+        Some(round) => {
+            lines.push(format!(
+                "block {}  bitwork {}.{}  {}",
+                round.height, round.bitwork.pre, round.bitwork.post_hex, round.remote_hash
+            ));
+            lines.push(format!(
+                "next block in {}  deadline in {}  {} hashes  {}",
+                format_countdown(round.next_block_time as i128 - now_nanos as i128),
+                format_countdown(round.dead_line as i128 - now_nanos as i128),
+                status.hashes,
+                format_hashrate(status.hashrate)
+            ));
+        }
+        None => {
+            lines.push(format!(
+                "block {}",
+                status
+                    .latest_block
+                    .map_or("-".to_string(), |b| b.to_string())
+            ));
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+pub fn round_line(round: &RoundRecord) -> String {
+    let result = match (&round.outcome, round.submission.as_ref()) {
+        (RoundOutcome::Found, None) => "found, submitting".to_string(),
+        (RoundOutcome::Found, Some(s)) => match (s.block_height, s.error.as_ref()) {
+            (Some(h), _) => format!("won, block {}", h),
+            (None, Some(e)) => format!("lost, {}", e),
+            (None, None) => "lost".to_string(),
+        },
+        (RoundOutcome::Expired, _) => "lost, deadline".to_string(),
+        (RoundOutcome::Cancelled, _) => "cancelled".to_string(),
+        (RoundOutcome::Failed(e), _) => format!("failed, {}", e),
+    };
+    format!(
+        "{:>8}  {:>14} hashes  {:>8}  {}",
+        round.height,
+        round.hashes,
+        format_cycles(round.cycles_price),
+        result
+    )
+}
+
+/// Hashrate and `DodMining.nonce` of each mining thread.
+pub fn thread_rates(
+    statuses: &BTreeMap<u32, ThreadStatus>,
+    now_secs: u64,
+) -> BTreeMap<u32, (f64, u32)> {
+    statuses
+        .iter()
+        .filter_map(|(i, s)| match s {
+            ThreadStatus::Mining(w) => {
+                let elapsed = Duration::from_secs(now_secs.saturating_sub(w.started_at).max(1));
+                Some((*i, (hashrate(w.hashes, elapsed), w.nonce)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The dashboard drawn by `run`, one indicatif bar per line.
+pub struct Dashboard {
+    mp: MultiProgress,
+    header: Vec<ProgressBar>,
+    threads: Vec<ProgressBar>,
+    rounds: Vec<ProgressBar>,
+    logs: Vec<ProgressBar>,
+    thread_style: ProgressStyle,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Dashboard::with_draw_target(ProgressDrawTarget::stderr())
+    }
+
+    pub fn with_draw_target(target: ProgressDrawTarget) -> Self {
+        let mp = MultiProgress::with_draw_target(target);
+        let text = |mp: &MultiProgress| {
+            mp.add(
+                ProgressBar::new(0).with_style(ProgressStyle::with_template("{wide_msg}").unwrap()),
+            )
+        };
+        let header = (0..3).map(|_| text(&mp)).collect();
+        let rounds_title = text(&mp);
+        rounds_title.set_message("recent rounds");
+        let mut rounds = vec![rounds_title];
+        rounds.extend((0..RECENT_ROUNDS).map(|_| text(&mp)));
+        let logs_title = text(&mp);
+        logs_title.set_message("log");
+        let mut logs = vec![logs_title];
+        logs.extend((0..LOG_TAIL_LINES).map(|_| text(&mp)));
+
+        Dashboard {
+            mp,
+            header,
+            threads: vec![],
+            rounds,
+            logs,
+            thread_style: ProgressStyle::with_template("{prefix:>10} {bar:40.cyan/blue} {msg}")
+                .unwrap()
+                .progress_chars("##-"),
+        }
+    }
+
+    pub fn draw(&mut self, engine: &MinerEngine, tail: &LogTail) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        for (bar, line) in self
+            .header
+            .iter()
+            .zip(header_lines(&engine.status(), engine.ic_now()))
+        {
+            bar.set_message(line);
+        }
+
+        let statuses = engine.thread_statuses();
+        self.resize_threads(statuses.len());
+        let rates = thread_rates(&statuses, now.as_secs());
+        let best = rates.values().map(|r| r.0).fold(1.0, f64::max);
+        for (i, bar) in self.threads.iter().enumerate() {
+            bar.set_length(best as u64);
+            match rates.get(&(i as u32)) {
+                Some((rate, nonce)) => {
+                    bar.set_position(*rate as u64);
+                    bar.set_message(format!("{} nonce {}", format_hashrate(*rate), nonce));
+                }
+                None => {
+                    bar.set_position(0);
+                    bar.set_message("idle");
+                }
+            }
+        }
+
+        let rounds = engine.rounds();
+        for (i, bar) in self.rounds.iter().skip(1).enumerate() {
+            // latest first
+            bar.set_message(
+                rounds
+                    .iter()
+                    .rev()
+                    .nth(i)
+                    .map(round_line)
+                    .unwrap_or_default(),
+            );
+        }
+
+        let lines = tail.lines();
+        for (i, bar) in self.logs.iter().skip(1).enumerate() {
+            bar.set_message(lines.get(i).cloned().unwrap_or_default());
+        }
+    }
+
+    fn resize_threads(&mut self, threads: usize) {
+        while self.threads.len() > threads {
+            let bar = self.threads.pop().unwrap();
+            bar.finish_and_clear();
+            self.mp.remove(&bar);
+        }
+        while self.threads.len() < threads {
+            let after = self.threads.last().unwrap_or(&self.header[2]).clone();
+            let bar = self.mp.insert_after(
+                &after,
+                ProgressBar::new(1).with_style(self.thread_style.clone()),
+            );
+            bar.set_prefix(format!("thread {}", self.threads.len()));
+            self.threads.push(bar);
+        }
+    }
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Dashboard::new()
+    }
+}
+
+/// Redraws the dashboard of `engine` until the process exits.
+pub async fn run(engine: MinerEngine, tail: LogTail) {
+    let mut dashboard = Dashboard::new();
+    loop {
+        dashboard.draw(&engine, &tail);
+        tokio::time::sleep(REDRAW_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::{RoundOutcome, RoundRecord, Submission};
+    use crate::tui::{format_countdown, round_line, thread_rates, LogTail};
+    use crate::types::{ThreadStatus, WorkerStatus};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn formats_countdown() {
+        assert_eq!(format_countdown(65_500_000_000), "1m05s");
+        assert_eq!(format_countdown(9_000_000_000), "9s");
+        assert_eq!(format_countdown(-1), "passed");
+    }
+
+    #[test]
+    fn log_tail_keeps_latest() {
+        let tail = LogTail::new(2);
+        tail.push("a".to_string());
+        tail.push("b".to_string());
+        tail.push("c".to_string());
+        assert_eq!(tail.lines(), vec!["b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn rounds_and_threads() {
+        let mut round = RoundRecord {
+            height: 12,
+            hashes: 1000,
+            outcome: RoundOutcome::Found,
+            cycles_price: 500_000_000_000,
+            ended_at: 0,
+            submission: None,
+        };
+        assert!(round_line(&round).ends_with("0.5T  found, submitting"));
+        round.submission = Some(Submission {
+            height: 12,
+            block_height: Some(12),
+            error: None,
+            at: 0,
+        });
+        assert!(round_line(&round).ends_with("won, block 12"));
+
+        let mut statuses = BTreeMap::new();
+        statuses.insert(0, ThreadStatus::Idle);
+        statuses.insert(
+            1,
+            ThreadStatus::Mining(WorkerStatus {
+                height: Some(12),
+                nonce: 2,
+                started_at: 100,
+                hashes: 500,
+            }),
+        );
+        let rates = thread_rates(&statuses, 110);
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[&1], (50.0, 2));
+    }
+}
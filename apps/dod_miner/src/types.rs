@@ -127,11 +127,33 @@ pub struct CheckJobs {
     pub submitted: u32,
 }
 
+/// Why a solution was not accepted by the canister.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SubmitError {
+    /// The composed commit tx failed the check before sending.
+    Invalid(String),
+    /// The call to the canister, or decoding its answer, failed.
+    Call(String),
+    /// The canister answered with an error.
+    Rejected(String),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::Invalid(e) | SubmitError::Call(e) | SubmitError::Rejected(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
 pub type WorkerJobToken = String;
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum ThreadStatus {
     Idle,
     Busy(WorkerJobToken),
+    Mining(WorkerStatus),
 }
 
 impl fmt::Display for ThreadStatus {
@@ -139,10 +161,27 @@ impl fmt::Display for ThreadStatus {
         match self {
             ThreadStatus::Idle => write!(f, "Idle"),
             ThreadStatus::Busy(r) => write!(f, "Busy: {}", r.clone()),
+            ThreadStatus::Mining(w) => write!(
+                f,
+                "Mining: block {}, nonce {}, {} hashes",
+                w.height.map_or("-".to_string(), |h| h.to_string()),
+                w.nonce,
+                w.hashes
+            ),
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct WorkerStatus {
+    pub height: Option<u64>,
+    /// `DodMining.nonce` of the commit tx the thread is searching.
+    pub nonce: u32,
+    /// Unix seconds the round started at.
+    pub started_at: u64,
+    pub hashes: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThreadResultExt {
     pub generated_nonce: u64,
@@ -421,6 +460,10 @@ pub struct ThreadResult {
     pub generated_nonce: u64,
     pub expired: bool,
     pub index: u32,
+    /// Hashes the thread has tried so far in this block.
+    pub hashes: u64,
+    /// `DodMining.nonce` of the chunk the thread is on.
+    pub nonce: u32,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -441,6 +484,15 @@ pub enum MiningResultType {
     Cpu(MiningResult),
 }
 
+impl MiningResultType {
+    /// The solution to submit, whichever backend found it.
+    pub fn mining_result(&self) -> &MiningResult {
+        match self {
+            MiningResultType::Cpu(r) => r,
+        }
+    }
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub struct MiningResultExt {
     pub result: MiningResultType,
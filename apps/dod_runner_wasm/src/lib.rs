@@ -1,3 +1,5 @@
+use dod_utils::bitwork::BitworkTarget;
+use dod_utils::mine::{MiningCursor, MiningStep};
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -10,4 +12,30 @@ use wasm_bindgen::prelude::*;
 #[wasm_bindgen]
 pub fn dod_runner(_nonce: u32, _time: u32, hashes: u64, tx: Vec<u8>, offset: usize, prefix: String, ext: Option<String>) -> Result<u64, String> {
     dod_utils::mine::mine_bitwork(tx, offset, hashes, prefix, ext)
-}
\ No newline at end of file
+}
+
+/// Mines in slices of at most `budget` hashes, so the host event loop stays responsive.
+#[wasm_bindgen]
+pub struct DodCursor(MiningCursor);
+
+#[wasm_bindgen]
+impl DodCursor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(tx: Vec<u8>, offset: usize, hashes: u64, prefix: String, ext: Option<String>) -> Result<DodCursor, String> {
+        let target = BitworkTarget::from_hex(&prefix, ext.as_deref())?;
+        Ok(DodCursor(MiningCursor::new(tx, offset, hashes, target)))
+    }
+
+    /// Returns the counter once one meets the bitwork, `None` while still searching.
+    pub fn step(&mut self, budget: u64) -> Result<Option<u64>, String> {
+        match self.0.step(budget) {
+            MiningStep::Found(n) => Ok(Some(n)),
+            MiningStep::Pending => Ok(None),
+            MiningStep::Exhausted => Err("The hashes has exceeded the allowed value of RBF (0xfffffffffffffffe)".into()),
+        }
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.0.counter
+    }
+}
@@ -1,5 +1,8 @@
 use flume::{Receiver, Sender};
 use indicatif::{MultiProgress, ProgressStyle};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
 use std::thread;
 
 pub fn get_multi_progress<T>() -> (MultiProgress, ProgressStyle, Sender<T>, Receiver<T>) {
@@ -15,15 +18,149 @@ pub fn get_multi_progress<T>() -> (MultiProgress, ProgressStyle, Sender<T>, Rece
     (m, sty, tx, rx)
 }
 
+/// Threads this process may run on: the cpus in its affinity mask, capped by the
+/// cgroup cpu quota when running in a container.
 pub fn get_available_threads() -> u32 {
-    if thread::available_parallelism().unwrap().get() == 1 {
-        1u32
+    let logical = thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1);
+    match get_cgroup_cpu_limit() {
+        Some(limit) => logical.min(limit).max(1),
+        None => logical,
+    }
+}
+
+/// Like `get_available_threads`, but counts SMT siblings of a core only once,
+/// and only the cores of the cpus in the affinity mask.
+pub fn get_physical_threads() -> u32 {
+    let available = get_available_threads();
+    let allowed = get_affinity_cpus();
+    let mut cores = BTreeSet::new();
+    if let Ok(entries) = fs::read_dir("/sys/devices/system/cpu") {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let cpu = match name
+                .strip_prefix("cpu")
+                .and_then(|n| n.parse::<usize>().ok())
+            {
+                Some(cpu) => cpu,
+                None => continue,
+            };
+            if allowed.as_ref().is_some_and(|a| !a.contains(&cpu)) {
+                continue;
+            }
+            let topology = entry.path().join("topology");
+            let package = fs::read_to_string(topology.join("physical_package_id"));
+            let core = fs::read_to_string(topology.join("core_id"));
+            if let (Ok(package), Ok(core)) = (package, core) {
+                cores.insert((package.trim().to_string(), core.trim().to_string()));
+            }
+        }
+    }
+    if cores.is_empty() {
+        available
     } else {
-        thread::available_parallelism().unwrap().get() as u32
+        available.min(cores.len() as u32).max(1)
+    }
+}
+
+/// The cpus in the affinity mask of the calling thread, `None` where it can't be read.
+#[cfg(target_os = "linux")]
+pub fn get_affinity_cpus() -> Option<BTreeSet<usize>> {
+    // SAFETY: cpu_set_t is plain data, and 0 stands for the calling thread
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some(
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_affinity_cpus() -> Option<BTreeSet<usize>> {
+    None
+}
+
+/// Whole cpus allowed by the cgroup (v2 `cpu.max`, or v1 cfs quota) of this
+/// process. A v2 cgroup is held to the quota of every cgroup above it as well,
+/// so this is the smallest quota from its own up to the root.
+pub fn get_cgroup_cpu_limit() -> Option<u32> {
+    let path = fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|s| {
+            s.lines()
+                .find_map(|l| l.strip_prefix("0::").map(|p| p.trim().to_string()))
+        })
+        .unwrap_or_default();
+
+    let quotas = cgroup_cpu_max(Path::new("/sys/fs/cgroup"), &path);
+    if !quotas.is_empty() {
+        return quotas.into_iter().flatten().min();
     }
+
+    let quota = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?;
+    let period = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?;
+    parse_cpu_max(&format!("{} {}", quota.trim(), period.trim()))
+}
+
+/// The parsed `cpu.max` of cgroup `path` under `root` and of each cgroup above
+/// it, skipping those without one.
+fn cgroup_cpu_max(root: &Path, path: &str) -> Vec<Option<u32>> {
+    let leaf = root.join(path.trim_start_matches('/'));
+    leaf.ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .filter_map(|dir| fs::read_to_string(dir.join("cpu.max")).ok())
+        .map(|s| parse_cpu_max(&s))
+        .collect()
+}
+
+/// Parses `"<quota> <period>"`, where a quota of `max` or `-1` means unlimited.
+pub fn parse_cpu_max(s: &str) -> Option<u32> {
+    let mut parts = s.split_whitespace();
+    let quota = parts.next()?.parse::<i64>().ok()?;
+    let period = parts.next()?.parse::<i64>().ok()?;
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(((quota + period - 1) / period) as u32)
 }
 
 pub fn get_single_progreses<T>() -> (Sender<T>, Receiver<T>) {
     let (tx, rx) = flume::unbounded::<T>();
     (tx, rx)
 }
+
+#[cfg(test)]
+mod test {
+    use crate::threads::{cgroup_cpu_max, parse_cpu_max};
+    use std::fs;
+
+    #[test]
+    fn cpu_max() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("-1 100000"), None);
+        assert_eq!(parse_cpu_max("200000 100000"), Some(2));
+        assert_eq!(parse_cpu_max("150000 100000"), Some(2));
+        assert_eq!(parse_cpu_max("50000 100000"), Some(1));
+    }
+
+    #[test]
+    fn cgroup_ancestors() {
+        let root = std::env::temp_dir().join(format!("dod_cgroup_{}", std::process::id()));
+        let leaf = root.join("system.slice/miner.service");
+        fs::create_dir_all(&leaf).unwrap();
+        fs::write(root.join("system.slice/cpu.max"), "200000 100000\n").unwrap();
+        fs::write(leaf.join("cpu.max"), "max 100000\n").unwrap();
+
+        let quotas = cgroup_cpu_max(&root, "/system.slice/miner.service");
+        assert_eq!(quotas, vec![None, Some(2)]);
+        assert_eq!(quotas.into_iter().flatten().min(), Some(2));
+        assert!(cgroup_cpu_max(&root, "/other.slice").is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -28,6 +28,13 @@ impl Ord for Bitwork {
 }
 
 impl Bitwork {
+    /// Average number of hashes needed to meet this bitwork: every prefix nibble
+    /// matches with 1/16, and the nibble after it is at least `post_hex`.
+    pub fn expected_hashes(&self) -> f64 {
+        let post = u64::from_str_radix(self.post_hex.as_str(), 16).unwrap_or(0);
+        16f64.powi(self.pre as i32) * 16.0 / (16 - post.min(15)) as f64
+    }
+
     #[allow(dead_code)]
     fn validate(&self) -> Result<(), String> {
         if self.pre > 64 {
@@ -95,6 +102,71 @@ impl Bitwork {
     }
 }
 
+/// A bitwork compiled against the block hash it applies to. A tx meets it when its
+/// txid, in the usual reversed hex form, starts with the first `len` nibbles of
+/// `prefix` and the nibble after them is at least `k`.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, Hash)]
+pub struct BitworkTarget {
+    pub prefix: Vec<u8>,
+    pub len: usize,
+    pub k: u8,
+}
+
+impl BitworkTarget {
+    /// The first `bitwork.pre` nibbles of `remote_hash` followed by `bitwork.post_hex`.
+    pub fn new(remote_hash: &[u8], bitwork: &Bitwork) -> Result<Self, String> {
+        if bitwork.pre > 64 || bitwork.pre as usize > remote_hash.len() * 2 {
+            return Err("Invalid bitwork".to_string());
+        }
+        let k = parse_post_hex(&bitwork.post_hex)?;
+        let len = bitwork.pre as usize;
+        let mut prefix = remote_hash[..len.div_ceil(2)].to_vec();
+        if len % 2 == 1 {
+            // only the high nibble of the last byte is part of the prefix
+            *prefix.last_mut().unwrap() &= 0xf0;
+        }
+        Ok(BitworkTarget { prefix, len, k })
+    }
+
+    /// Compiles a prefix given as hex, such as `"7777"` with `Some("a")` for 7777.a.
+    pub fn from_hex(prefix: &str, post_hex: Option<&str>) -> Result<Self, String> {
+        let padded = format!("{:0<1$}", prefix, prefix.len().div_ceil(2) * 2);
+        let bytes = hex::decode(padded).map_err(|_| "Invalid bitwork".to_string())?;
+        Ok(BitworkTarget {
+            prefix: bytes,
+            len: prefix.len(),
+            k: post_hex.map_or(Ok(0), parse_post_hex)?,
+        })
+    }
+
+    /// `hash` is a raw sha256d digest, in the byte order it is computed in.
+    pub fn matches(&self, hash: &[u8]) -> bool {
+        if self.len / 2 >= hash.len() {
+            // the whole hash is fixed, nothing is left for the post nibble
+            let last = hash.len() - 1;
+            return (0..hash.len()).all(|i| hash[last - i] == self.prefix[i]);
+        }
+        compare_bitwork_range(hash, &self.prefix, self.len, self.k)
+    }
+
+    /// `txid` is hex in the reversed form explorers and wallets show.
+    pub fn matches_txid(&self, txid: &str) -> Result<bool, String> {
+        let mut hash = hex::decode(txid).map_err(|_| "Invalid current hash".to_string())?;
+        if hash.len() != 32 {
+            return Err("Invalid current hash width".to_string());
+        }
+        hash.reverse();
+        Ok(self.matches(&hash))
+    }
+}
+
+fn parse_post_hex(post_hex: &str) -> Result<u8, String> {
+    if post_hex.len() != 1 {
+        return Err("Invalid bitwork".to_string());
+    }
+    u8::from_str_radix(post_hex, 16).map_err(|_| "Invalid bitwork".to_string())
+}
+
 pub fn bitwork_from_height(block_height: u64, difficulty_epoch: u64) -> Result<Bitwork, String> {
     if difficulty_epoch == 0 {
         return Err("Invalid difficulty epoch".to_string());
@@ -183,49 +255,21 @@ pub fn bitwork_match_hash(
         target.reverse();
     }
 
-    let target_string = hex::encode(target);
-    let binding1 = current_hash.clone();
-    let current_pre = binding1.get(..bitwork.pre as usize);
-
-    if current_pre.is_none() {
+    let pre = bitwork.pre as usize;
+    // the canister has always rejected a bitwork that leaves no post nibble
+    if pre >= 64 {
         return Err("Invalid bitwork".to_string());
     }
-    let binding2 = current_hash.clone();
-
-    let current_post = binding2.get(bitwork.pre as usize..bitwork.pre as usize + 1);
-    if current_post.is_none() {
-        return Err("Invalid bitwork".to_string());
-    }
-
-    let binding3 = target_string.clone();
-    let target_pre = binding3.get(..bitwork.pre as usize);
-    if target_pre.is_none() {
-        return Err("Invalid bitwork".to_string());
-    }
-
-    let binding4 = target_string.clone();
-
-    let target_post = binding4.get(bitwork.pre as usize..bitwork.pre as usize + 1);
-    if target_post.is_none() {
-        return Err("Invalid bitwork".to_string());
-    }
-
+    let target_string = hex::encode(&target);
     ic_cdk::println!(
         "current_pre: {:?}, target_pre: {:?}, current_post: {:?}, target_post: {:?}",
-        current_pre,
-        target_pre,
-        current_post,
-        target_post
+        current_hash.get(..pre),
+        target_string.get(..pre),
+        current_hash.get(pre..pre + 1),
+        target_string.get(pre..pre + 1)
     );
 
-    if current_pre.unwrap() == target_pre.unwrap()
-        && u32::from_str_radix(current_post.unwrap(), 16).unwrap()
-            >= u32::from_str_radix(bitwork.post_hex.as_str(), 16).unwrap()
-    {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+    BitworkTarget::new(&target, &bitwork)?.matches_txid(current_hash.as_str())
 }
 
 pub fn merge_bitwork(bitwork_height: Bitwork, bitwork_tx: Bitwork) -> Bitwork {
@@ -322,4 +366,60 @@ mod tests {
         assert!(compare_bitwork_range(&a_1, &b_1, 9, 8));
         assert!(compare_bitwork_range(&a_1, &b_1, 9, 10));
     }
+
+    #[test]
+    fn test_bitwork_target() {
+        let remote_hash =
+            hex::decode("98799b250c911fe0df86cd59066e329d93bfb3d35fa57cdd3b243e2a8eec1b45")
+                .unwrap();
+        let bitwork = Bitwork {
+            pre: 5,
+            post_hex: "8".to_string(),
+        };
+        let target = BitworkTarget::new(&remote_hash, &bitwork).unwrap();
+        assert_eq!(target, BitworkTarget::from_hex("98799", Some("8")).unwrap());
+
+        let txid = |s: &str| format!("{}{}", s, "0".repeat(64 - s.len()));
+        assert_eq!(target.matches_txid(&txid("987998")), Ok(true));
+        assert_eq!(target.matches_txid(&txid("98799f")), Ok(true));
+        assert_eq!(target.matches_txid(&txid("987997")), Ok(false));
+        assert_eq!(target.matches_txid(&txid("98798f")), Ok(false));
+
+        // the verification used by the canister agrees with the miner
+        for t in ["987998", "98799f", "987997", "98798f"] {
+            assert_eq!(
+                bitwork_match_hash(txid(t), hex::encode(&remote_hash), bitwork.clone(), false),
+                target.matches_txid(&txid(t))
+            );
+        }
+
+        let full = Bitwork {
+            pre: 64,
+            post_hex: "0".to_string(),
+        };
+        let target = BitworkTarget::new(&remote_hash, &full).unwrap();
+        assert_eq!(target.matches_txid(&hex::encode(&remote_hash)), Ok(true));
+        // the canister's verification keeps rejecting it
+        let same = hex::encode(&remote_hash);
+        assert_eq!(
+            bitwork_match_hash(same.clone(), same, full, false),
+            Err("Invalid bitwork".to_string())
+        );
+        assert!(BitworkTarget::new(&remote_hash[..2], &bitwork).is_err());
+    }
+
+    #[test]
+    fn test_expected_hashes() {
+        let bitwork = Bitwork {
+            pre: 2,
+            post_hex: "0".to_string(),
+        };
+        assert_eq!(bitwork.expected_hashes(), 256.0);
+
+        let bitwork = Bitwork {
+            pre: 2,
+            post_hex: "8".to_string(),
+        };
+        assert_eq!(bitwork.expected_hashes(), 512.0);
+    }
 }
@@ -0,0 +1,42 @@
+use super::K;
+use std::arch::aarch64::*;
+
+/// Compresses each 64-byte block of `blocks` into `state` using the ARMv8 crypto extensions.
+#[target_feature(enable = "sha2")]
+pub(super) unsafe fn compress(state: &mut [u32; 8], blocks: &[u8]) {
+    let mut abcd = vld1q_u32(state.as_ptr());
+    let mut efgh = vld1q_u32(state.as_ptr().add(4));
+
+    for block in blocks.chunks_exact(64) {
+        let abcd_save = abcd;
+        let efgh_save = efgh;
+
+        let p = block.as_ptr();
+        let mut msg = [
+            vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(p))),
+            vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(p.add(16)))),
+            vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(p.add(32)))),
+            vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(p.add(48)))),
+        ];
+
+        for j in 0..16 {
+            let wk = vaddq_u32(msg[j % 4], vld1q_u32(K.as_ptr().add(j * 4)));
+            if j < 12 {
+                msg[j % 4] = vsha256su1q_u32(
+                    vsha256su0q_u32(msg[j % 4], msg[(j + 1) % 4]),
+                    msg[(j + 2) % 4],
+                    msg[(j + 3) % 4],
+                );
+            }
+            let abcd_prev = abcd;
+            abcd = vsha256hq_u32(abcd, efgh, wk);
+            efgh = vsha256h2q_u32(efgh, abcd_prev, wk);
+        }
+
+        abcd = vaddq_u32(abcd, abcd_save);
+        efgh = vaddq_u32(efgh, efgh_save);
+    }
+
+    vst1q_u32(state.as_mut_ptr(), abcd);
+    vst1q_u32(state.as_mut_ptr().add(4), efgh);
+}
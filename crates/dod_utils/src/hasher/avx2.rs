@@ -0,0 +1,130 @@
+use super::{IV, K};
+use std::arch::x86_64::*;
+
+macro_rules! rotr {
+    ($x:expr, $n:literal, $m:literal) => {
+        _mm256_or_si256(_mm256_srli_epi32::<$n>($x), _mm256_slli_epi32::<$m>($x))
+    };
+}
+
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn add3(a: __m256i, b: __m256i, c: __m256i) -> __m256i {
+    _mm256_add_epi32(_mm256_add_epi32(a, b), c)
+}
+
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn xor3(a: __m256i, b: __m256i, c: __m256i) -> __m256i {
+    _mm256_xor_si256(_mm256_xor_si256(a, b), c)
+}
+
+/// One sha256 compression over eight independent lanes, with `w` holding the
+/// big-endian message words of each lane.
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn compress8(state: &mut [__m256i; 8], w: &mut [__m256i; 16]) {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+    for i in 0..64 {
+        if i >= 16 {
+            let w15 = w[(i + 1) % 16];
+            let w2 = w[(i + 14) % 16];
+            let s0 = xor3(
+                rotr!(w15, 7, 25),
+                rotr!(w15, 18, 14),
+                _mm256_srli_epi32::<3>(w15),
+            );
+            let s1 = xor3(
+                rotr!(w2, 17, 15),
+                rotr!(w2, 19, 13),
+                _mm256_srli_epi32::<10>(w2),
+            );
+            w[i % 16] = add3(w[i % 16], s0, _mm256_add_epi32(w[(i + 9) % 16], s1));
+        }
+
+        let s1 = xor3(rotr!(e, 6, 26), rotr!(e, 11, 21), rotr!(e, 25, 7));
+        let ch = _mm256_xor_si256(_mm256_and_si256(e, f), _mm256_andnot_si256(e, g));
+        let t1 = add3(
+            _mm256_add_epi32(h, s1),
+            ch,
+            _mm256_add_epi32(_mm256_set1_epi32(K[i] as i32), w[i % 16]),
+        );
+        let s0 = xor3(rotr!(a, 2, 30), rotr!(a, 13, 19), rotr!(a, 22, 10));
+        let maj = xor3(
+            _mm256_and_si256(a, b),
+            _mm256_and_si256(a, c),
+            _mm256_and_si256(b, c),
+        );
+        let t2 = _mm256_add_epi32(s0, maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = _mm256_add_epi32(d, t1);
+        d = c;
+        c = b;
+        b = a;
+        a = _mm256_add_epi32(t1, t2);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = _mm256_add_epi32(*s, v);
+    }
+}
+
+/// Finishes sha256d for eight padded tails that share the same `midstate`.
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn sha256d_x8(
+    midstate: &[u32; 8],
+    lanes: &[Vec<u8>; 8],
+    out: &mut [[u8; 32]; 8],
+) {
+    let word = |l: usize, at: usize| -> i32 {
+        i32::from_be_bytes(lanes[l][at..at + 4].try_into().unwrap())
+    };
+
+    let mut state = [_mm256_setzero_si256(); 8];
+    for (s, m) in state.iter_mut().zip(midstate) {
+        *s = _mm256_set1_epi32(*m as i32);
+    }
+    let mut w = [_mm256_setzero_si256(); 16];
+    for block in (0..lanes[0].len()).step_by(64) {
+        for (j, wj) in w.iter_mut().enumerate() {
+            let at = block + j * 4;
+            *wj = _mm256_set_epi32(
+                word(7, at),
+                word(6, at),
+                word(5, at),
+                word(4, at),
+                word(3, at),
+                word(2, at),
+                word(1, at),
+                word(0, at),
+            );
+        }
+        compress8(&mut state, &mut w);
+    }
+
+    // the 32-byte digests fit in a single padded block
+    w[..8].copy_from_slice(&state);
+    w[8] = _mm256_set1_epi32(0x80000000u32 as i32);
+    for wj in w[9..15].iter_mut() {
+        *wj = _mm256_setzero_si256();
+    }
+    w[15] = _mm256_set1_epi32(256);
+    for (s, iv) in state.iter_mut().zip(IV) {
+        *s = _mm256_set1_epi32(iv as i32);
+    }
+    compress8(&mut state, &mut w);
+
+    let mut words = [[0u32; 8]; 8];
+    for (j, v) in state.iter().enumerate() {
+        _mm256_storeu_si256(words[j].as_mut_ptr() as *mut __m256i, *v);
+    }
+    for (l, hash) in out.iter_mut().enumerate() {
+        for (j, chunk) in hash.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&words[j][l].to_be_bytes());
+        }
+    }
+}
@@ -0,0 +1,305 @@
+mod portable;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "x86_64")]
+mod avx2;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+use crate::mine::sha256_prefix_engine;
+use bitcoin::hashes::{sha256, HashEngine};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Number of nonces hashed per call of [`NonceTemplate::hash_batch`] by the mining loops.
+pub const HASH_BATCH: usize = 8;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub enum HashBackend {
+    /// `bitcoin::hashes`, available everywhere.
+    Portable,
+    /// x86 SHA extensions.
+    ShaNi,
+    /// ARMv8 crypto extensions.
+    ArmSha2,
+    /// 8-lane AVX2 multi-buffer, hashes 8 nonces at once.
+    Avx2,
+}
+
+impl HashBackend {
+    pub const ALL: [HashBackend; 4] = [
+        HashBackend::Portable,
+        HashBackend::ShaNi,
+        HashBackend::ArmSha2,
+        HashBackend::Avx2,
+    ];
+
+    /// Picks the fastest backend the running cpu supports.
+    pub fn detect() -> Self {
+        [HashBackend::ShaNi, HashBackend::ArmSha2, HashBackend::Avx2]
+            .into_iter()
+            .find(|b| b.is_available())
+            .unwrap_or(HashBackend::Portable)
+    }
+
+    pub fn available() -> Vec<HashBackend> {
+        HashBackend::ALL
+            .into_iter()
+            .filter(|b| b.is_available())
+            .collect()
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self {
+            HashBackend::Portable => true,
+            #[cfg(target_arch = "x86_64")]
+            HashBackend::ShaNi => {
+                is_x86_feature_detected!("sha")
+                    && is_x86_feature_detected!("sse2")
+                    && is_x86_feature_detected!("ssse3")
+                    && is_x86_feature_detected!("sse4.1")
+            }
+            #[cfg(target_arch = "x86_64")]
+            HashBackend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            HashBackend::ArmSha2 => std::arch::is_aarch64_feature_detected!("sha2"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Nonces hashed per invocation of the underlying compression routine.
+    pub fn lanes(&self) -> usize {
+        match self {
+            HashBackend::Avx2 => 8,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for HashBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashBackend::Portable => write!(f, "portable"),
+            HashBackend::ShaNi => write!(f, "sha-ni"),
+            HashBackend::ArmSha2 => write!(f, "armv8-sha2"),
+            HashBackend::Avx2 => write!(f, "avx2x8"),
+        }
+    }
+}
+
+/// A commit tx split at the 64-byte block that holds its 8-byte counter. The
+/// fixed prefix is compressed once; each nonce only finishes the padded tail.
+#[derive(Clone)]
+pub struct NonceTemplate {
+    engine: sha256::HashEngine,
+    midstate: [u32; 8],
+    tail: Vec<u8>,
+    lanes: [Vec<u8>; 8],
+    counter: usize,
+}
+
+impl NonceTemplate {
+    pub fn new(tx: &[u8], offset: usize) -> Self {
+        let (engine, prefix_len) = sha256_prefix_engine(tx, offset);
+
+        let mut midstate = [0u32; 8];
+        let bytes = engine.midstate().to_byte_array();
+        for (word, chunk) in midstate.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        let tail = tx[prefix_len..].to_vec();
+        let mut padded = tail.clone();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        padded.extend_from_slice(&(tx.len() as u64 * 8).to_be_bytes());
+
+        NonceTemplate {
+            engine,
+            midstate,
+            tail,
+            lanes: std::array::from_fn(|_| padded.clone()),
+            counter: offset - prefix_len,
+        }
+    }
+
+    /// Writes `sha256d(tx)` for the counters `first..first + out.len()` into `out`.
+    /// A backend the running cpu lacks falls back to [`HashBackend::Portable`].
+    pub fn hash_batch(&mut self, backend: HashBackend, first: u64, out: &mut [[u8; 32]]) {
+        // the simd routines are only safe to call once the cpu is known to have them
+        let backend = if backend.is_available() {
+            backend
+        } else {
+            HashBackend::Portable
+        };
+        let (s, e) = (self.counter, self.counter + 8);
+        match backend {
+            HashBackend::Portable => {
+                for (i, hash) in out.iter_mut().enumerate() {
+                    self.tail[s..e].copy_from_slice(&first.wrapping_add(i as u64).to_le_bytes());
+                    *hash = portable::sha256d(&self.engine, &self.tail);
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            HashBackend::ShaNi => {
+                for (i, hash) in out.iter_mut().enumerate() {
+                    let padded = &mut self.lanes[0];
+                    padded[s..e].copy_from_slice(&first.wrapping_add(i as u64).to_le_bytes());
+                    *hash = unsafe { sha256d_with(x86::compress, &self.midstate, padded) };
+                }
+            }
+            #[cfg(target_arch = "aarch64")]
+            HashBackend::ArmSha2 => {
+                for (i, hash) in out.iter_mut().enumerate() {
+                    let padded = &mut self.lanes[0];
+                    padded[s..e].copy_from_slice(&first.wrapping_add(i as u64).to_le_bytes());
+                    *hash = unsafe { sha256d_with(aarch64::compress, &self.midstate, padded) };
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            HashBackend::Avx2 => {
+                for (c, chunk) in out.chunks_mut(8).enumerate() {
+                    let base = first.wrapping_add(c as u64 * 8);
+                    for (l, padded) in self.lanes.iter_mut().enumerate() {
+                        padded[s..e].copy_from_slice(&base.wrapping_add(l as u64).to_le_bytes());
+                    }
+                    let mut hashes = [[0u8; 32]; 8];
+                    unsafe { avx2::sha256d_x8(&self.midstate, &self.lanes, &mut hashes) };
+                    chunk.copy_from_slice(&hashes[..chunk.len()]);
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("hash backend {} is not supported on this target", backend),
+        }
+    }
+}
+
+/// Finishes the first sha256 from `midstate` over the padded tail, then hashes the
+/// 32-byte digest again, using a single-lane compression function.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+unsafe fn sha256d_with(
+    compress: unsafe fn(&mut [u32; 8], &[u8]),
+    midstate: &[u32; 8],
+    padded: &[u8],
+) -> [u8; 32] {
+    let mut state = *midstate;
+    compress(&mut state, padded);
+
+    let mut block = [0u8; 64];
+    for (chunk, word) in block.chunks_exact_mut(4).zip(state.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    block[32] = 0x80;
+    block[62] = 0x01; // 256 bit message length
+
+    let mut state = IV;
+    compress(&mut state, &block);
+
+    let mut hash = [0u8; 32];
+    for (chunk, word) in hash.chunks_exact_mut(4).zip(state.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitwork::compare_bitwork_range;
+    use crate::mine::{easy_bitwork_2, mine_bitwork_raw_dead_line_with};
+    use crate::sha256d;
+
+    const TX: &str = "01000000011d345364868f17be20373460fe022fc54243cf749ea888e97dcc24873691168b0000000000fdffffff03b0040000000000002251201a6b8cce40e18cc56ce97592b6d579bd0f0bc716383b1beda3a53da2a25fd11b00000000000000000a6a089d4b1212d0c917e64e5201000000000022512061f023b192540b40b459e9aa62aedceb874e6ea599723d21aa7274e5ddc3be8900000000";
+
+    #[test]
+    fn backends_match_sha256d() {
+        let tx = hex::decode(TX).unwrap();
+        // cover a counter that straddles a block boundary as well as the real one
+        for offset in [101, 60] {
+            let mut template = NonceTemplate::new(&tx, offset);
+            for backend in HashBackend::available() {
+                let mut out = [[0u8; 32]; 11];
+                template.hash_batch(backend, u64::MAX - 5, &mut out);
+                for (i, hash) in out.iter().enumerate() {
+                    let mut full = tx.clone();
+                    let counter = (u64::MAX - 5).wrapping_add(i as u64);
+                    full[offset..offset + 8].copy_from_slice(&counter.to_le_bytes());
+                    assert_eq!(hash.to_vec(), sha256d(&full), "backend {}", backend);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unavailable_backends_fall_back() {
+        let tx = hex::decode(TX).unwrap();
+        let mut template = NonceTemplate::new(&tx, 101);
+        let mut expected = [[0u8; 32]; 3];
+        template.hash_batch(HashBackend::Portable, 7, &mut expected);
+        for backend in HashBackend::ALL {
+            let mut out = [[0u8; 32]; 3];
+            template.hash_batch(backend, 7, &mut out);
+            assert_eq!(out, expected, "backend {}", backend);
+        }
+    }
+
+    #[test]
+    fn backends_find_same_nonce() {
+        let tx = hex::decode(TX).unwrap();
+        let bitwork = easy_bitwork_2("1234", 3, Some("4".to_string())).unwrap();
+        let expected = mine_bitwork_raw_dead_line_with(
+            tx.clone(),
+            101,
+            1,
+            bitwork.clone(),
+            None,
+            None,
+            HashBackend::Portable,
+        )
+        .unwrap();
+
+        let mut full = tx.clone();
+        full[101..109].copy_from_slice(&expected.to_le_bytes());
+        assert!(compare_bitwork_range(
+            &sha256d(&full),
+            &bitwork.prefix,
+            bitwork.len,
+            bitwork.k
+        ));
+
+        for backend in HashBackend::available() {
+            let found = mine_bitwork_raw_dead_line_with(
+                tx.clone(),
+                101,
+                1,
+                bitwork.clone(),
+                None,
+                None,
+                backend,
+            )
+            .unwrap();
+            assert_eq!(found, expected, "backend {}", backend);
+        }
+    }
+}
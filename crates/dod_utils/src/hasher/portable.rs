@@ -0,0 +1,10 @@
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+/// Resumes `engine` (which has consumed the fixed prefix) over the unpadded tail.
+pub(super) fn sha256d(engine: &sha256::HashEngine, tail: &[u8]) -> [u8; 32] {
+    let mut engine = engine.clone();
+    engine.input(tail);
+    sha256::Hash::from_engine(engine)
+        .hash_again()
+        .to_byte_array()
+}
@@ -0,0 +1,57 @@
+use super::K;
+use std::arch::x86_64::*;
+
+/// Compresses each 64-byte block of `blocks` into `state` using the SHA extensions.
+/// Follows Intel's reference layout, keeping the state as ABEF/CDGH.
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+pub(super) unsafe fn compress(state: &mut [u32; 8], blocks: &[u8]) {
+    let mask = _mm_set_epi64x(0x0c0d0e0f08090a0bu64 as i64, 0x0405060700010203u64 as i64);
+
+    let tmp = _mm_shuffle_epi32::<0xb1>(_mm_loadu_si128(state.as_ptr() as *const __m128i));
+    let efgh = _mm_shuffle_epi32::<0x1b>(_mm_loadu_si128(state.as_ptr().add(4) as *const __m128i));
+    let mut state0 = _mm_alignr_epi8::<8>(tmp, efgh);
+    let mut state1 = _mm_blend_epi16::<0xf0>(efgh, tmp);
+
+    for block in blocks.chunks_exact(64) {
+        let abef_save = state0;
+        let cdgh_save = state1;
+
+        let p = block.as_ptr() as *const __m128i;
+        let mut msg = [
+            _mm_shuffle_epi8(_mm_loadu_si128(p), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(p.add(1)), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(p.add(2)), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(p.add(3)), mask),
+        ];
+
+        for j in 0..16 {
+            if j >= 4 {
+                let t = _mm_add_epi32(
+                    _mm_sha256msg1_epu32(msg[j % 4], msg[(j + 1) % 4]),
+                    _mm_alignr_epi8::<4>(msg[(j + 3) % 4], msg[(j + 2) % 4]),
+                );
+                msg[j % 4] = _mm_sha256msg2_epu32(t, msg[(j + 3) % 4]);
+            }
+            let wk = _mm_add_epi32(
+                msg[j % 4],
+                _mm_loadu_si128(K.as_ptr().add(j * 4) as *const __m128i),
+            );
+            state1 = _mm_sha256rnds2_epu32(state1, state0, wk);
+            state0 = _mm_sha256rnds2_epu32(state0, state1, _mm_shuffle_epi32::<0x0e>(wk));
+        }
+
+        state0 = _mm_add_epi32(state0, abef_save);
+        state1 = _mm_add_epi32(state1, cdgh_save);
+    }
+
+    let feba = _mm_shuffle_epi32::<0x1b>(state0);
+    let dchg = _mm_shuffle_epi32::<0xb1>(state1);
+    _mm_storeu_si128(
+        state.as_mut_ptr() as *mut __m128i,
+        _mm_blend_epi16::<0xf0>(feba, dchg),
+    );
+    _mm_storeu_si128(
+        state.as_mut_ptr().add(4) as *mut __m128i,
+        _mm_alignr_epi8::<8>(dchg, feba),
+    );
+}
@@ -1,8 +1,33 @@
-use crate::bitwork::{compare_bitwork_range, Bitwork};
-use bitcoin::hashes::{sha256, sha256d, Hash};
+use crate::bitwork::{Bitwork, BitworkTarget};
+use crate::hasher::{HashBackend, NonceTemplate, HASH_BATCH};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::SystemTime;
 
+/// Counters at or above this value would disable RBF on the commit tx.
+pub const RBF_LIMIT: u64 = 0xfffffffffffffffe;
+
+/// Shared flag that asks every mining loop holding a clone of it to stop.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 fn hex_to_vec(hex_string: &str) -> Option<Vec<u8>> {
     let mut bytes = Vec::new();
 
@@ -25,11 +50,27 @@ pub struct BitworkResult {
     pub max: u8,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, Hash)]
-pub struct BitworkResult2 {
-    pub prefix: Vec<u8>,
-    pub len: usize,
-    pub k: u8,
+/// Kept for callers of `easy_bitwork_2`, every matcher now takes a [`BitworkTarget`].
+pub type BitworkResult2 = BitworkTarget;
+
+impl From<BitworkResult> for BitworkTarget {
+    /// `easy_bitwork` keeps an odd prefix nibble, or the post nibble, in `min..=max`.
+    fn from(bitwork: BitworkResult) -> Self {
+        let mut prefix = bitwork.prefix[..bitwork.len].to_vec();
+        let mut len = bitwork.len * 2;
+        let k = if bitwork.max == 0 {
+            0
+        } else if bitwork.max == 0xff && bitwork.min & 0x0f == 0 {
+            bitwork.min >> 4
+        } else if bitwork.min >> 4 == bitwork.max >> 4 && bitwork.max & 0x0f == 0x0f {
+            prefix.push(bitwork.min & 0xf0);
+            len += 1;
+            bitwork.min & 0x0f
+        } else {
+            bitwork.min >> 4
+        };
+        BitworkTarget { prefix, len, k }
+    }
 }
 
 pub fn easy_bitwork_2(
@@ -101,10 +142,11 @@ pub fn mine_bitwork(
     prefix: String,
     ext: Option<String>,
 ) -> Result<u64, String> {
-    let bitwork = easy_bitwork(&prefix, ext);
-    mine_bitwork_raw(tx, offset, hashes, bitwork)
+    let bitwork = BitworkTarget::from_hex(&prefix, ext.as_deref())?;
+    mine_bitwork_raw_dead_line(tx, offset, hashes, bitwork, None, None)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn mine_bitwork_with_deadline(
     tx: Vec<u8>,
     offset: usize,
@@ -113,30 +155,83 @@ pub fn mine_bitwork_with_deadline(
     string_width: u64,
     ext: Option<String>,
     deadline: u128,
+    cancel: Option<&CancelToken>,
 ) -> Result<u64, String> {
     let bitwork = easy_bitwork_2(&prefix, string_width, ext);
     if bitwork.is_none() {
         Err("invalid bitwork".into())
     } else {
-        mine_bitwork_raw_dead_line(tx, offset, hashes, bitwork.unwrap(), Some(deadline))
+        mine_bitwork_raw_dead_line(tx, offset, hashes, bitwork.unwrap(), Some(deadline), cancel)
     }
 }
 
+/// Returns a sha256 engine that has already consumed every full 64-byte block
+/// of `tx` before `offset`, together with the number of bytes it consumed.
+pub fn sha256_prefix_engine(tx: &[u8], offset: usize) -> (sha256::HashEngine, usize) {
+    let prefix_len = offset - offset % 64;
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tx[..prefix_len]);
+    (engine, prefix_len)
+}
+
 pub fn mine_bitwork_raw_dead_line(
     tx: Vec<u8>,
     offset: usize,
     hashes: u64,
     bitwork: BitworkResult2,
     deadline: Option<u128>,
+    cancel: Option<&CancelToken>,
 ) -> Result<u64, String> {
-    let mut tx = tx.clone();
-    let s = offset;
-    let e = offset + 8;
-    let mut hashes: u64 = hashes;
-    let mut new_tx_hash: [u8; 32];
-    let mut sha2_hash: sha256::Hash;
-    let mut sha2d_hash: sha256d::Hash;
-    let mut hashes_bytes: [u8; 8];
+    mine_bitwork_raw_dead_line_with(
+        tx,
+        offset,
+        hashes,
+        bitwork,
+        deadline,
+        cancel,
+        HashBackend::detect(),
+    )
+}
+
+pub fn mine_bitwork_raw_dead_line_with(
+    tx: Vec<u8>,
+    offset: usize,
+    hashes: u64,
+    bitwork: BitworkResult2,
+    deadline: Option<u128>,
+    cancel: Option<&CancelToken>,
+    backend: HashBackend,
+) -> Result<u64, String> {
+    match mine_bitwork_range(
+        &tx,
+        offset,
+        hashes..RBF_LIMIT,
+        &bitwork,
+        deadline,
+        cancel,
+        backend,
+    )? {
+        Some(hashes) => Ok(hashes),
+        None => Err("The hashes has exceeded the allowed value of RBF (0xfffffffffffffffe)".into()),
+    }
+}
+
+/// Searches the counters in `range`. Returns `Ok(None)` once the range is exhausted,
+/// and the next untried counter as `Err` on deadline or cancellation.
+pub fn mine_bitwork_range(
+    tx: &[u8],
+    offset: usize,
+    range: Range<u64>,
+    bitwork: &BitworkResult2,
+    deadline: Option<u128>,
+    cancel: Option<&CancelToken>,
+    backend: HashBackend,
+) -> Result<Option<u64>, String> {
+    // everything before the 64-byte block holding the counter is fixed,
+    // so the template hashes it once and only finishes the tail per attempt
+    let mut template = NonceTemplate::new(tx, offset);
+    let mut hashes: u64 = range.start;
+    let mut batch = [[0u8; 32]; HASH_BATCH];
     loop {
         if let Some(deadline) = deadline {
             if SystemTime::now()
@@ -148,26 +243,101 @@ pub fn mine_bitwork_raw_dead_line(
                 return Err(hashes.to_string());
             }
         }
+        if cancel.is_some_and(|c| c.is_cancelled()) {
+            return Err(hashes.to_string());
+        }
 
-        // update tx
-        hashes_bytes = hashes.to_le_bytes();
-        tx[s..e].copy_from_slice(&hashes_bytes);
-
-        // double sha256 and reverse
-        sha2_hash = Hash::hash(&tx);
-        sha2d_hash = sha2_hash.hash_again();
-        new_tx_hash = sha2d_hash.to_byte_array();
+        // never hash past the end of the range, which is at most the RBF limit
+        let n = range
+            .end
+            .min(RBF_LIMIT)
+            .saturating_sub(hashes)
+            .min(HASH_BATCH as u64) as usize;
+        if n == 0 {
+            return Ok(None);
+        }
+        template.hash_batch(backend, hashes, &mut batch[..n]);
 
-        if compare_bitwork_range(&new_tx_hash, &bitwork.prefix, bitwork.len, bitwork.k) {
-            return Ok(hashes);
+        for (i, new_tx_hash) in batch[..n].iter().enumerate() {
+            if bitwork.matches(new_tx_hash) {
+                return Ok(Some(hashes + i as u64));
+            }
         }
 
         // increment hashes
-        hashes += 1;
-        if hashes == 0xfffffffffffffffe {
-            return Err(
-                "The hashes has exceeded the allowed value of RBF (0xfffffffffffffffe)".into(),
-            );
+        hashes += n as u64;
+    }
+}
+
+/// Outcome of one [`MiningCursor::step`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum MiningStep {
+    /// The counter meets the target, stepping again continues after it.
+    Found(u64),
+    /// The budget ran out, the cursor resumes where it stopped.
+    Pending,
+    /// Every counter up to the end of the range has been tried.
+    Exhausted,
+}
+
+/// Mining progress that can be advanced a bounded number of hashes at a time.
+/// It uses no threads and no clock, and serializes for checkpoints, so it can be
+/// driven from an event loop, a wasm host or interleaved with other work.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MiningCursor {
+    pub tx: Vec<u8>,
+    pub offset: usize,
+    /// Next counter to try.
+    pub counter: u64,
+    /// Counters stop before this value.
+    pub end: u64,
+    pub target: BitworkTarget,
+}
+
+impl MiningCursor {
+    pub fn new(tx: Vec<u8>, offset: usize, counter: u64, target: BitworkTarget) -> Self {
+        MiningCursor {
+            tx,
+            offset,
+            counter,
+            end: RBF_LIMIT,
+            target,
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.counter >= self.end.min(RBF_LIMIT)
+    }
+
+    /// Tries at most `budget` counters.
+    pub fn step(&mut self, budget: u64) -> MiningStep {
+        self.step_with(budget, HashBackend::detect())
+    }
+
+    pub fn step_with(&mut self, budget: u64, backend: HashBackend) -> MiningStep {
+        let end = self.counter.saturating_add(budget).min(self.end);
+        // without a deadline or a cancel token the range search cannot fail
+        match mine_bitwork_range(
+            &self.tx,
+            self.offset,
+            self.counter..end,
+            &self.target,
+            None,
+            None,
+            backend,
+        ) {
+            Ok(Some(found)) => {
+                self.counter = found + 1;
+                MiningStep::Found(found)
+            }
+            _ => {
+                self.counter = self.counter.max(end);
+                if self.is_exhausted() {
+                    MiningStep::Exhausted
+                } else {
+                    MiningStep::Pending
+                }
+            }
         }
     }
 }
@@ -178,51 +348,122 @@ pub fn mine_bitwork_raw(
     hashes: u64,
     bitwork: BitworkResult,
 ) -> Result<u64, String> {
-    let mut tx = tx.clone();
-    let s = offset;
-    let e = offset + 8;
-    let mut hashes: u64 = hashes;
-    let mut new_tx_hash: [u8; 32];
-    let mut sha2_hash: sha256::Hash;
-    let mut sha2d_hash: sha256d::Hash;
-    let mut hashes_bytes: [u8; 8];
-    loop {
-        // update tx
-        hashes_bytes = hashes.to_le_bytes();
-        tx[s..e].copy_from_slice(&hashes_bytes);
-
-        // double sha256 and reverse
-        sha2_hash = Hash::hash(&tx);
-        sha2d_hash = sha2_hash.hash_again();
-        new_tx_hash = sha2d_hash.to_byte_array();
-        new_tx_hash.reverse();
-
-        // check if txid has valid bitwork
-        if new_tx_hash[0..bitwork.len] == bitwork.prefix {
-            if bitwork.max == 0 {
-                return Ok(hashes);
-            }
-            let val = new_tx_hash[bitwork.len];
-            if val >= bitwork.min && val <= bitwork.max {
-                return Ok(hashes);
-            }
+    mine_bitwork_raw_dead_line(tx, offset, hashes, bitwork.into(), None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitwork::{compare_bitwork_range, BitworkTarget};
+    use crate::hasher::HashBackend;
+    use crate::mine::{
+        easy_bitwork, easy_bitwork_2, mine_bitwork_range, mine_bitwork_raw_dead_line,
+        mine_bitwork_with_deadline, CancelToken, MiningCursor, MiningStep,
+    };
+    use crate::sha256d;
+    use std::time::SystemTime;
+
+    const TX: &str = "01000000011d345364868f17be20373460fe022fc54243cf749ea888e97dcc24873691168b0000000000fdffffff03b0040000000000002251201a6b8cce40e18cc56ce97592b6d579bd0f0bc716383b1beda3a53da2a25fd11b00000000000000000a6a089d4b1212d0c917e64e5201000000000022512061f023b192540b40b459e9aa62aedceb874e6ea599723d21aa7274e5ddc3be8900000000";
+
+    #[test]
+    fn cancelled_loop_stops() {
+        let tx = hex::decode(TX).unwrap();
+        // 32 hex chars of prefix would never be found without cancellation
+        let bitwork = easy_bitwork_2("12345678123456781234567812345678", 32, None).unwrap();
+        let cancel = CancelToken::new();
+
+        let worker = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                mine_bitwork_raw_dead_line(tx, 101, 1, bitwork, None, Some(&cancel))
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        cancel.cancel();
+
+        let hashes = worker.join().unwrap().unwrap_err();
+        assert!(hashes.parse::<u64>().unwrap() > 1);
+    }
+
+    #[test]
+    fn midstate_matches_full_hash() {
+        let tx = hex::decode(TX).unwrap();
+        let offset = 101;
+        let bitwork = easy_bitwork_2("1234", 3, Some("4".to_string())).unwrap();
+
+        let found =
+            mine_bitwork_raw_dead_line(tx.clone(), offset, 1, bitwork.clone(), None, None).unwrap();
+
+        // every counter up to and including the solution must agree with a full rehash
+        let mut full = tx.clone();
+        for n in 1..=found {
+            full[offset..offset + 8].copy_from_slice(&n.to_le_bytes());
+            let matched =
+                compare_bitwork_range(&sha256d(&full), &bitwork.prefix, bitwork.len, bitwork.k);
+            assert_eq!(matched, n == found);
         }
+    }
 
-        // increment hashes
-        hashes += 1;
-        if hashes == 0xfffffffffffffffe {
-            return Err(
-                "The hashes has exceeded the allowed value of RBF (0xfffffffffffffffe)".into(),
+    #[test]
+    fn easy_bitwork_compiles_to_same_target() {
+        for (prefix, ext) in [
+            ("7777", Some("a")),
+            ("7777", None),
+            ("aabbccd", None),
+            ("aabbccd", Some("7")),
+            ("123456", Some("f")),
+        ] {
+            let target: BitworkTarget = easy_bitwork(prefix, ext.map(|e| e.to_string())).into();
+            assert_eq!(
+                target,
+                BitworkTarget::from_hex(prefix, ext).unwrap(),
+                "{} {:?}",
+                prefix,
+                ext
             );
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::bitwork::compare_bitwork_range;
-    use crate::mine::{easy_bitwork, easy_bitwork_2, mine_bitwork_with_deadline};
-    use std::time::SystemTime;
+    #[test]
+    fn cursor_resumes_in_small_steps() {
+        let tx = hex::decode(TX).unwrap();
+        let bitwork = easy_bitwork_2("1234", 3, Some("4".to_string())).unwrap();
+        let found =
+            mine_bitwork_raw_dead_line(tx.clone(), 101, 1, bitwork.clone(), None, None).unwrap();
+
+        let mut cursor = MiningCursor::new(tx, 101, 1, bitwork);
+        let mut steps = 0;
+        let res = loop {
+            // checkpoint and restore between every step
+            let mut restored = cursor.clone();
+            let step = restored.step(100);
+            cursor = restored;
+            steps += 1;
+            if step != MiningStep::Pending {
+                break step;
+            }
+        };
+        assert_eq!(res, MiningStep::Found(found));
+        assert_eq!(cursor.counter, found + 1);
+        assert_eq!(steps, (found - 1) / 100 + 1);
+
+        cursor.end = cursor.counter + 10;
+        assert_eq!(cursor.step(1000), MiningStep::Exhausted);
+        assert!(cursor.is_exhausted());
+    }
+
+    #[test]
+    fn range_stops_at_its_end() {
+        let tx = hex::decode(TX).unwrap();
+        let bitwork = easy_bitwork_2("1234", 3, Some("4".to_string())).unwrap();
+        let backend = HashBackend::detect();
+
+        let found =
+            mine_bitwork_raw_dead_line(tx.clone(), 101, 1, bitwork.clone(), None, None).unwrap();
+        let before = mine_bitwork_range(&tx, 101, 1..found, &bitwork, None, None, backend);
+        assert_eq!(before, Ok(None));
+        let within = mine_bitwork_range(&tx, 101, found..found + 1, &bitwork, None, None, backend);
+        assert_eq!(within, Ok(Some(found)));
+    }
 
     #[test]
     fn easy_bitwork2() {
@@ -289,7 +530,7 @@ mod tests {
         //     easy_bitwork("1234", Some("1".to_string()))
         // );
 
-        let tx2 = hex::decode("01000000011d345364868f17be20373460fe022fc54243cf749ea888e97dcc24873691168b0000000000fdffffff03b0040000000000002251201a6b8cce40e18cc56ce97592b6d579bd0f0bc716383b1beda3a53da2a25fd11b00000000000000000a6a089d4b1212d0c917e64e5201000000000022512061f023b192540b40b459e9aa62aedceb874e6ea599723d21aa7274e5ddc3be8900000000").unwrap();
+        let tx2 = hex::decode(TX).unwrap();
 
         let i2 = mine_bitwork_with_deadline(
             tx2,
@@ -303,6 +544,7 @@ mod tests {
                 .unwrap()
                 .as_nanos()
                 + 10000000000000,
+            None,
         )
         .unwrap();
         println!("{:?}", i2.to_le_bytes() as [u8; 8]);
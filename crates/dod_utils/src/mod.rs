@@ -1,5 +1,6 @@
 pub mod bitwork;
 pub mod error;
+pub mod hasher;
 pub mod mine;
 pub mod types;
 